@@ -0,0 +1,434 @@
+use clap::Args;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::{anyhow, bail, Context};
+use directories::ProjectDirs;
+use log::{info, debug, warn};
+use signature::Signer;
+use ssh_encoding::{Decode, Encode};
+use ssh_key::private::{EcdsaKeypair, KeypairData};
+use ssh_key::{Algorithm, Certificate, PrivateKey, Signature};
+
+use crate::config::{Config, DEFAULT_PROFILE};
+use crate::files;
+use crate::http::HttpClient;
+use crate::renewal;
+use crate::oidc::get_access_token;
+use crate::token_storage::TokenStorage;
+use crate::ssh::{generate_keypair, request_certificate};
+
+// Message numbers from the ssh-agent protocol (draft-miller-ssh-agent)
+pub const SSH_AGENT_FAILURE: u8 = 5;
+pub const SSH_AGENT_SUCCESS: u8 = 6;
+pub const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+pub const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
+const SSH_AGENTC_ADD_ID_CONSTRAINED: u8 = 25;
+const SSH_AGENT_CONSTRAIN_LIFETIME: u8 = 1;
+pub const SSH_AGENT_RSA_SHA2_256: u32 = 2;
+const SSH_AGENT_RSA_SHA2_512: u32 = 4;
+
+/// Comment of the identity served by `cscs-key agent`
+const AGENT_IDENTITY_COMMENT: &str = "cscs-key agent";
+
+/// Upper bound for a single agent message, as in OpenSSH
+pub const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+/// Key blob and comment of an identity held by the agent
+pub struct Identity {
+    pub key_blob: Vec<u8>,
+    pub comment: String,
+}
+
+/// Minimal client for the ssh-agent protocol over a Unix socket
+pub struct AgentClient {
+    stream: UnixStream,
+}
+
+impl AgentClient {
+    pub fn connect(socket: &Path) -> anyhow::Result<Self> {
+        let stream = UnixStream::connect(socket)
+            .with_context(|| format!("Failed to connect to ssh-agent at {}", socket.display()))?;
+        Ok(Self { stream })
+    }
+
+    /// Connect to the agent from `SSH_AUTH_SOCK`
+    pub fn from_env() -> anyhow::Result<Self> {
+        let socket = std::env::var_os("SSH_AUTH_SOCK")
+            .context("SSH_AUTH_SOCK is not set, no ssh-agent is running")?;
+        Self::connect(Path::new(&socket))
+    }
+
+    pub fn identities(&mut self) -> anyhow::Result<Vec<Identity>> {
+        let response = self.request(&[SSH_AGENTC_REQUEST_IDENTITIES])?;
+        let (kind, mut body) = response.split_first().context("Empty ssh-agent response")?;
+        if *kind != SSH_AGENT_IDENTITIES_ANSWER {
+            bail!("Unexpected ssh-agent response {} to identities request", kind);
+        }
+
+        let count = u32::decode(&mut body)?;
+        let mut identities = Vec::new();
+        for _ in 0..count {
+            identities.push(Identity {
+                key_blob: Vec::<u8>::decode(&mut body)?,
+                comment: String::decode(&mut body)?,
+            });
+        }
+        Ok(identities)
+    }
+
+    /// Add a private key together with its certificate, removed by the agent after `lifetime` seconds
+    pub fn add_certificate(
+        &mut self,
+        private_key: &PrivateKey,
+        cert: &Certificate,
+        comment: &str,
+        lifetime: Option<u32>,
+    ) -> anyhow::Result<()> {
+        let mut message = vec![SSH_AGENTC_ADD_ID_CONSTRAINED];
+        cert.algorithm().to_certificate_type().encode(&mut message)?;
+        cert.to_bytes()?.encode(&mut message)?;
+        encode_certificate_private_key(private_key.key_data(), &mut message)?;
+        comment.encode(&mut message)?;
+        if let Some(seconds) = lifetime {
+            SSH_AGENT_CONSTRAIN_LIFETIME.encode(&mut message)?;
+            seconds.encode(&mut message)?;
+        }
+
+        self.expect_success(&message)
+            .context("ssh-agent refused to add the certificate")
+    }
+
+    /// Sign `data` with the identity `key_blob`, returning the encoded signature
+    pub fn sign(&mut self, key_blob: &[u8], data: &[u8], flags: u32) -> anyhow::Result<Vec<u8>> {
+        let mut message = vec![SSH_AGENTC_SIGN_REQUEST];
+        key_blob.encode(&mut message)?;
+        data.encode(&mut message)?;
+        flags.encode(&mut message)?;
+
+        let response = self.request(&message)?;
+        let (kind, mut body) = response.split_first().context("Empty ssh-agent response")?;
+        match *kind {
+            SSH_AGENT_SIGN_RESPONSE => Ok(Vec::<u8>::decode(&mut body)?),
+            SSH_AGENT_FAILURE => bail!("ssh-agent refused to sign"),
+            other => bail!("Unexpected ssh-agent response {} to sign request", other),
+        }
+    }
+
+    pub fn remove(&mut self, key_blob: &[u8]) -> anyhow::Result<()> {
+        let mut message = vec![SSH_AGENTC_REMOVE_IDENTITY];
+        key_blob.encode(&mut message)?;
+        self.expect_success(&message)
+            .context("ssh-agent refused to remove the identity")
+    }
+
+    fn expect_success(&mut self, message: &[u8]) -> anyhow::Result<()> {
+        match self.request(message)?.first() {
+            Some(&SSH_AGENT_SUCCESS) => Ok(()),
+            Some(&SSH_AGENT_FAILURE) => bail!("ssh-agent returned failure"),
+            other => bail!("Unexpected ssh-agent response {:?}", other),
+        }
+    }
+
+    fn request(&mut self, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+        write_message(&mut self.stream, message)?;
+        read_message(&mut self.stream)?.ok_or_else(|| anyhow!("ssh-agent closed the connection"))
+    }
+}
+
+// Private key fields that follow the certificate blob in an add-identity request
+fn encode_certificate_private_key(key_data: &KeypairData, message: &mut Vec<u8>) -> anyhow::Result<()> {
+    match key_data {
+        KeypairData::Ed25519(keypair) => keypair.encode(message)?,
+        KeypairData::Ecdsa(EcdsaKeypair::NistP256 { private, .. }) => private.encode(message)?,
+        KeypairData::Ecdsa(EcdsaKeypair::NistP384 { private, .. }) => private.encode(message)?,
+        KeypairData::Ecdsa(EcdsaKeypair::NistP521 { private, .. }) => private.encode(message)?,
+        KeypairData::Rsa(keypair) => keypair.private.encode(message)?,
+        KeypairData::Encrypted(_) => bail!("The private key is encrypted"),
+        _ => bail!("Unsupported key algorithm for ssh-agent"),
+    }
+    Ok(())
+}
+
+pub fn write_message(stream: &mut impl Write, message: &[u8]) -> anyhow::Result<()> {
+    let len = u32::try_from(message.len())?;
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(message)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Read one length-prefixed message, `None` on a clean end of stream
+pub fn read_message(stream: &mut impl Read) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_MESSAGE_LEN {
+        bail!("Invalid ssh-agent message length {}", len);
+    }
+
+    let mut message = vec![0u8; len];
+    stream.read_exact(&mut message)?;
+    debug!("ssh-agent message type {}", message[0]);
+    Ok(Some(message))
+}
+
+#[derive(Args, Debug)]
+pub struct AgentArgs {
+    #[arg(long, value_name = "PATH", help = "Socket to listen on [default: $XDG_RUNTIME_DIR/cscs-key/agent.sock]")]
+    socket: Option<PathBuf>,
+    #[arg(long, help = "Do not detach, serve the agent from this process")]
+    foreground: bool,
+}
+
+/// Key pair held in memory by the agent and the certificate currently issued for it
+struct AgentIdentity {
+    private_key: PrivateKey,
+    cert: Certificate,
+}
+
+/// Serve an ssh-agent holding a key that never touches the disk, re-signing its certificate before it expires
+pub fn run_agent(config: &Config, args: &AgentArgs) -> anyhow::Result<()> {
+    debug!("ssh-key agent subcommand");
+    debug!("{:?}", config);
+
+    let socket = match &args.socket {
+        Some(socket) => socket.clone(),
+        None => default_socket_path(&config.profile)?,
+    };
+    if socket.exists() {
+        if UnixStream::connect(&socket).is_ok() {
+            bail!("An agent is already listening on {}", socket.display());
+        }
+        info!("Removing stale socket {}", socket.display());
+        fs::remove_file(&socket)?;
+    }
+
+    let http_client = HttpClient::new(config)?;
+    if args.foreground {
+        serve(config, &http_client, &socket)
+    } else {
+        spawn_detached(config, &http_client, args, &socket)
+    }
+}
+
+// Log in while a terminal is available, then restart ourselves in the background
+fn spawn_detached(config: &Config, http_client: &HttpClient, args: &AgentArgs, socket: &Path) -> anyhow::Result<()> {
+    TokenStorage::from_config(config)?.check_background()?;
+    get_access_token(config, http_client)?;
+
+    let proj_dirs = project_dirs()?;
+    files::create_dir(proj_dirs.cache_dir())?;
+    let log_path = proj_dirs.cache_dir().join("agent.log");
+    let log = fs::OpenOptions::new().create(true).append(true).mode(files::PRIVATE).open(&log_path)
+        .with_context(|| format!("Failed to open agent log {}", log_path.display()))?;
+    // The mode only applies when the file is created, an existing log may be readable by others
+    log.set_permissions(fs::Permissions::from_mode(files::PRIVATE))?;
+
+    // Keep the global options (URLs, key type, ...) of this invocation
+    let mut command = Command::new(std::env::current_exe()?);
+    command.args(std::env::args_os().skip(1)).arg("--foreground");
+    if args.socket.is_none() {
+        command.arg("--socket").arg(socket);
+    }
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(log)
+        .process_group(0)
+        .spawn()
+        .context("Failed to start the agent process")?;
+
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while UnixStream::connect(socket).is_err() {
+        if let Some(status) = child.try_wait()? {
+            bail!("The agent exited during startup ({}). See {} for details.", status, log_path.display());
+        }
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            bail!("Timed out waiting for the agent to start. See {} for details.", log_path.display());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    print_environment(socket, child.id());
+    Ok(())
+}
+
+fn serve(config: &Config, http_client: &HttpClient, socket: &Path) -> anyhow::Result<()> {
+    let margin = renewal::renew_margin(config)?;
+    let identity = issue_identity(config, http_client, None)?;
+    let cert = identity.cert.clone();
+    let identity = Mutex::new(identity);
+
+    if let Some(parent) = socket.parent() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+    }
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+    info!("Agent listening on {}", socket.display());
+
+    print_environment(socket, std::process::id());
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| renew(config, http_client, &identity, cert, margin));
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let identity = &identity;
+                    scope.spawn(move || {
+                        if let Err(e) = handle_client(stream, identity) {
+                            debug!("Agent connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept agent connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Generate a key pair (unless renewing) and have the SSH service sign it
+fn issue_identity(config: &Config, http_client: &HttpClient, private_key: Option<PrivateKey>) -> anyhow::Result<AgentIdentity> {
+    let private_key = match private_key {
+        Some(private_key) => private_key,
+        None => {
+            info!("Generating new {} key pair", config.key_type);
+            let mut private_key = generate_keypair(&config.key_type)?;
+            private_key.set_comment(AGENT_IDENTITY_COMMENT);
+            private_key
+        }
+    };
+
+    let signed_key = request_certificate(config, http_client, &private_key.public_key().to_openssh()?)?;
+    let cert = Certificate::from_openssh(signed_key.public_key.trim())
+        .map_err(|e| anyhow!("Failed to parse the issued certificate: {}", e))?;
+    if cert.public_key() != private_key.public_key().key_data() {
+        bail!("The issued certificate does not match the agent key");
+    }
+    info!("Certificate {} valid until {}", cert.serial(), signed_key.expire_time);
+
+    Ok(AgentIdentity { private_key, cert })
+}
+
+// Re-sign the certificate of the agent key before it expires
+fn renew(config: &Config, http_client: &HttpClient, identity: &Mutex<AgentIdentity>, cert: Certificate, margin: Duration) -> ! {
+    renewal::keep_renewed(margin, Some(cert), || {
+        let private_key = identity.lock().unwrap().private_key.clone();
+        let renewed = issue_identity(config, http_client, Some(private_key))?;
+        let cert = renewed.cert.clone();
+        *identity.lock().unwrap() = renewed;
+        Ok(cert)
+    })
+}
+
+fn handle_client(mut stream: UnixStream, identity: &Mutex<AgentIdentity>) -> anyhow::Result<()> {
+    while let Some(message) = read_message(&mut stream)? {
+        let response = match message[0] {
+            SSH_AGENTC_REQUEST_IDENTITIES => identities_answer(&identity.lock().unwrap())?,
+            SSH_AGENTC_SIGN_REQUEST => sign_response(&identity.lock().unwrap(), &message[1..])
+                .unwrap_or_else(|e| {
+                    info!("Refusing sign request: {}", e);
+                    vec![SSH_AGENT_FAILURE]
+                }),
+            // Adding or removing keys, locking and extensions are not supported
+            _ => vec![SSH_AGENT_FAILURE],
+        };
+        write_message(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+fn identities_answer(identity: &AgentIdentity) -> anyhow::Result<Vec<u8>> {
+    let mut response = vec![SSH_AGENT_IDENTITIES_ANSWER];
+    if is_expired(&identity.cert) {
+        0u32.encode(&mut response)?;
+    } else {
+        1u32.encode(&mut response)?;
+        identity.cert.to_bytes()?.encode(&mut response)?;
+        AGENT_IDENTITY_COMMENT.encode(&mut response)?;
+    }
+    Ok(response)
+}
+
+fn sign_response(identity: &AgentIdentity, mut body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let key_blob = Vec::<u8>::decode(&mut body)?;
+    let data = Vec::<u8>::decode(&mut body)?;
+    let flags = u32::decode(&mut body)?;
+
+    // Clients may refer to the identity by its certificate or by the plain public key
+    if key_blob != identity.cert.to_bytes()? && key_blob != identity.private_key.public_key().to_bytes()? {
+        bail!("unknown key");
+    }
+    if is_expired(&identity.cert) {
+        bail!("the certificate has expired");
+    }
+    // ssh-key only produces rsa-sha2-512 signatures for RSA keys
+    if matches!(identity.private_key.algorithm(), Algorithm::Rsa { .. }) && flags & SSH_AGENT_RSA_SHA2_512 == 0 {
+        bail!("only rsa-sha2-512 signatures are supported");
+    }
+
+    let signature: Signature = identity.private_key.try_sign(&data)?;
+    let mut blob = Vec::new();
+    signature.encode(&mut blob)?;
+    let mut response = vec![SSH_AGENT_SIGN_RESPONSE];
+    blob.encode(&mut response)?;
+    Ok(response)
+}
+
+fn is_expired(cert: &Certificate) -> bool {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    cert.valid_before() <= now
+}
+
+fn print_environment(socket: &Path, pid: u32) {
+    println!("SSH_AUTH_SOCK={}; export SSH_AUTH_SOCK;", shell_quote(&socket.display().to_string()));
+    println!("CSCS_KEY_AGENT_PID={}; export CSCS_KEY_AGENT_PID;", pid);
+    println!("echo cscs-key agent pid {};", pid);
+}
+
+fn shell_quote(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii_alphanumeric() || "/._-+:@%".contains(c)) {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+fn project_dirs() -> anyhow::Result<ProjectDirs> {
+    ProjectDirs::from("ch", "cscs", "cscs-key").context("Could not determine cache directory")
+}
+
+fn default_socket_path(profile: &str) -> anyhow::Result<PathBuf> {
+    let dir = match project_dirs()?.runtime_dir() {
+        Some(dir) => dir.to_path_buf(),
+        // No XDG_RUNTIME_DIR, use a private directory per agent
+        None => std::env::temp_dir().join(format!("cscs-key-{}", std::process::id())),
+    };
+    if profile == DEFAULT_PROFILE {
+        Ok(dir.join("agent.sock"))
+    } else {
+        Ok(dir.join(format!("agent-{}.sock", profile)))
+    }
+}
@@ -0,0 +1,285 @@
+use std::fmt;
+use std::time::Duration;
+use log::debug;
+use reqwest::StatusCode;
+use reqwest::blocking::Response;
+use serde::de::DeserializeOwned;
+
+use crate::http::retry_after;
+
+/// Error responses of the SSH service and the token endpoints
+#[derive(Debug)]
+pub enum ApiError {
+    /// The access token or API key was rejected (401)
+    Unauthorized { message: String },
+    /// Authenticated, but not allowed to perform the request (403)
+    Forbidden { message: String },
+    /// The service rejected the requested key validity (the `duration` field)
+    ValidityTooLong { message: String },
+    /// Too many requests (429)
+    RateLimited { retry_after: Option<Duration>, message: String },
+    /// The service failed or is unavailable (5xx)
+    ServerError { status: StatusCode, message: String },
+    /// Any other rejected request (4xx)
+    Rejected { status: StatusCode, message: String },
+    /// A successful response whose body could not be understood
+    MalformedResponse { message: String },
+}
+
+impl ApiError {
+    /// Process exit code, distinct for every class of error
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ApiError::Unauthorized { .. } => 10,
+            ApiError::Forbidden { .. } => 11,
+            ApiError::ValidityTooLong { .. } => 12,
+            ApiError::RateLimited { .. } => 13,
+            ApiError::ServerError { .. } => 14,
+            ApiError::Rejected { .. } => 15,
+            ApiError::MalformedResponse { .. } => 16,
+        }
+    }
+
+    /// Typed error for an OAuth 2.0 error response of a token, device or revocation endpoint
+    pub fn from_oauth(error: &str, description: Option<&str>) -> Self {
+        let message = oauth_message(error, description);
+        oauth_error(error, message.clone())
+            .unwrap_or(ApiError::Rejected { status: StatusCode::BAD_REQUEST, message })
+    }
+
+    fn from_response(response: Response) -> Self {
+        let status = response.status();
+        let retry_after = retry_after(response.headers());
+        let body = response.text().unwrap_or_default();
+        debug!("Error response {}: {}", status, body);
+        let json: Option<serde_json::Value> = serde_json::from_str(&body).ok();
+
+        // Token endpoints answer 400 or 401 with an OAuth error code that tells more than the status
+        let oauth = json.as_ref().and_then(|json| {
+            let error = json.get("error")?.as_str()?;
+            let description = json.get("error_description").and_then(|description| description.as_str());
+            oauth_error(error, oauth_message(error, description))
+        });
+        if let Some(error) = oauth {
+            return error;
+        }
+
+        let message = error_message(&body);
+        match status {
+            StatusCode::UNAUTHORIZED => ApiError::Unauthorized { message },
+            StatusCode::FORBIDDEN => ApiError::Forbidden { message },
+            StatusCode::TOO_MANY_REQUESTS => ApiError::RateLimited { retry_after, message },
+            StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY
+                if json.as_ref().is_some_and(|json| rejects_field(json, VALIDITY_FIELD)) =>
+            {
+                ApiError::ValidityTooLong { message }
+            }
+            status if status.is_server_error() => ApiError::ServerError { status, message },
+            status => ApiError::Rejected { status, message },
+        }
+    }
+}
+
+/// Request field of the SSH service carrying the key validity
+const VALIDITY_FIELD: &str = "duration";
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Unauthorized { message } => {
+                write!(f, "Authentication failed: {}. Log in again or check the API key.", message)
+            }
+            ApiError::Forbidden { message } => write!(f, "Access denied: {}", message),
+            ApiError::ValidityTooLong { message } => {
+                write!(f, "Key validity rejected: {}. Request a shorter validity with --key-validity.", message)
+            }
+            ApiError::RateLimited { retry_after: Some(retry_after), message } => {
+                write!(f, "Rate limited: {}. Try again in {} seconds.", message, retry_after.as_secs())
+            }
+            ApiError::RateLimited { retry_after: None, message } => {
+                write!(f, "Rate limited: {}. Try again later.", message)
+            }
+            ApiError::ServerError { status, message } => {
+                write!(f, "The service is unavailable (HTTP {}): {}", status, message)
+            }
+            ApiError::Rejected { status, message } => {
+                write!(f, "Request rejected (HTTP {}): {}", status, message)
+            }
+            ApiError::MalformedResponse { message } => write!(f, "Unexpected response from the service: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Turn a non-success response into an `ApiError`
+pub fn check_response(response: Response) -> Result<Response, ApiError> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(ApiError::from_response(response))
+    }
+}
+
+/// Check the status and decode the JSON body of a response
+pub fn parse_json<T: DeserializeOwned>(response: Response) -> Result<T, ApiError> {
+    let response = check_response(response)?;
+    let body = response.text()
+        .map_err(|e| ApiError::MalformedResponse { message: e.to_string() })?;
+    serde_json::from_str(&body).map_err(|e| {
+        debug!("Malformed response body: {}", body);
+        ApiError::MalformedResponse { message: e.to_string() }
+    })
+}
+
+/// Exit code for an error returned by a command, 1 unless it was caused by an `ApiError`
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    error.chain()
+        .find_map(|cause| cause.downcast_ref::<ApiError>())
+        .map_or(1, ApiError::exit_code)
+}
+
+// Servers report errors as {"message": ..}, {"detail": ..} or OAuth style {"error", "error_description"}
+fn error_message(body: &str) -> String {
+    let json: Option<serde_json::Value> = serde_json::from_str(body).ok();
+    let field = |name: &str| json.as_ref()
+        .and_then(|json| json.get(name))
+        .and_then(|value| value.as_str())
+        .filter(|value| !value.is_empty())
+        .map(str::to_string);
+
+    field("message")
+        .or_else(|| field("detail"))
+        .or_else(|| field("error_description"))
+        .or_else(|| field("error"))
+        .unwrap_or_else(|| {
+            let body = body.trim();
+            if body.is_empty() {
+                "no details provided".to_string()
+            } else {
+                body.chars().take(500).collect()
+            }
+        })
+}
+
+// OAuth 2.0 error codes (RFC 6749, RFC 7009, RFC 8628) with a meaning of their own
+fn oauth_error(error: &str, message: String) -> Option<ApiError> {
+    match error {
+        // Expired, revoked or already redeemed refresh tokens and authorization codes
+        "invalid_grant" | "invalid_token" | "expired_token" => Some(ApiError::Unauthorized { message }),
+        "invalid_client" | "unauthorized_client" | "access_denied" | "insufficient_scope" => {
+            Some(ApiError::Forbidden { message })
+        }
+        "server_error" => Some(ApiError::ServerError { status: StatusCode::INTERNAL_SERVER_ERROR, message }),
+        "temporarily_unavailable" => Some(ApiError::ServerError { status: StatusCode::SERVICE_UNAVAILABLE, message }),
+        "invalid_request" | "invalid_scope" | "unsupported_grant_type" | "unsupported_token_type" => {
+            Some(ApiError::Rejected { status: StatusCode::BAD_REQUEST, message })
+        }
+        _ => None,
+    }
+}
+
+fn oauth_message(error: &str, description: Option<&str>) -> String {
+    match description {
+        Some(description) if !description.is_empty() => format!("{} ({})", description, error),
+        _ => error.to_string(),
+    }
+}
+
+// Whether the error names `field` as the rejected one, directly or in google.rpc.BadRequest details
+fn rejects_field(json: &serde_json::Value, field: &str) -> bool {
+    let names_field = |value: &serde_json::Value| value.get("field").and_then(|name| name.as_str()) == Some(field);
+    let violations = json.get("details")
+        .and_then(|details| details.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|detail| detail.get("fieldViolations")?.as_array())
+        .flatten();
+    names_field(json) || violations.into_iter().any(names_field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    fn response(status: u16, body: &str) -> Response {
+        openidconnect::http::Response::builder()
+            .status(status)
+            .body(body.to_string())
+            .unwrap()
+            .into()
+    }
+
+    fn error(status: u16, body: &str) -> ApiError {
+        check_response(response(status, body)).unwrap_err()
+    }
+
+    #[test]
+    fn error_message_prefers_structured_fields() {
+        assert_eq!(error_message(r#"{"message": "key not found", "error": "not_found"}"#), "key not found");
+        assert_eq!(error_message(r#"{"detail": "bad duration"}"#), "bad duration");
+        assert_eq!(error_message(r#"{"error": "invalid_grant", "error_description": "Token expired"}"#), "Token expired");
+        assert_eq!(error_message(r#"{"error": "invalid_grant", "error_description": ""}"#), "invalid_grant");
+        assert_eq!(error_message("  upstream timed out\n"), "upstream timed out");
+        assert_eq!(error_message(""), "no details provided");
+        assert_eq!(error_message(&"x".repeat(600)).len(), 500);
+    }
+
+    #[test]
+    fn status_codes_map_to_error_classes() {
+        assert!(matches!(error(401, r#"{"message": "expired"}"#), ApiError::Unauthorized { message } if message == "expired"));
+        assert!(matches!(error(403, ""), ApiError::Forbidden { .. }));
+        assert!(matches!(error(429, ""), ApiError::RateLimited { retry_after: None, .. }));
+        assert!(matches!(error(503, ""), ApiError::ServerError { status: StatusCode::SERVICE_UNAVAILABLE, .. }));
+        assert!(matches!(error(404, ""), ApiError::Rejected { status: StatusCode::NOT_FOUND, .. }));
+    }
+
+    #[test]
+    fn validity_errors_are_detected_by_field() {
+        assert!(matches!(error(400, r#"{"message": "too long", "field": "duration"}"#), ApiError::ValidityTooLong { .. }));
+        let bad_request = r#"{"code": 3, "message": "invalid argument",
+            "details": [{"fieldViolations": [{"field": "duration", "description": "at most 1d"}]}]}"#;
+        assert!(matches!(error(400, bad_request), ApiError::ValidityTooLong { .. }));
+        assert!(matches!(error(400, r#"{"message": "invalid duration"}"#), ApiError::Rejected { .. }));
+        assert!(matches!(error(400, r#"{"field": "publicKey"}"#), ApiError::Rejected { .. }));
+    }
+
+    #[test]
+    fn oauth_errors_map_to_error_classes() {
+        let refresh_expired = r#"{"error": "invalid_grant", "error_description": "Token is not active"}"#;
+        assert!(matches!(error(400, refresh_expired),
+            ApiError::Unauthorized { message } if message == "Token is not active (invalid_grant)"));
+        assert!(matches!(error(401, r#"{"error": "invalid_client"}"#), ApiError::Forbidden { .. }));
+        assert!(matches!(error(400, r#"{"error": "temporarily_unavailable"}"#), ApiError::ServerError { .. }));
+        assert!(matches!(error(400, r#"{"error": "invalid_request"}"#), ApiError::Rejected { .. }));
+        // Not an OAuth error code, the status decides
+        assert!(matches!(error(401, r#"{"error": "Unauthorized"}"#), ApiError::Unauthorized { .. }));
+        assert!(matches!(ApiError::from_oauth("invalid_grant", None), ApiError::Unauthorized { .. }));
+        assert!(matches!(ApiError::from_oauth("custom_error", None), ApiError::Rejected { .. }));
+    }
+
+    #[test]
+    fn exit_codes_are_distinct_and_found_in_the_chain() {
+        let errors = [
+            ApiError::Unauthorized { message: String::new() },
+            ApiError::Forbidden { message: String::new() },
+            ApiError::ValidityTooLong { message: String::new() },
+            ApiError::RateLimited { retry_after: None, message: String::new() },
+            ApiError::ServerError { status: StatusCode::BAD_GATEWAY, message: String::new() },
+            ApiError::Rejected { status: StatusCode::NOT_FOUND, message: String::new() },
+            ApiError::MalformedResponse { message: String::new() },
+        ];
+        let mut codes: Vec<u8> = errors.iter().map(ApiError::exit_code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+        assert!(codes.iter().all(|code| *code > 1));
+
+        let wrapped = Err::<(), _>(ApiError::Forbidden { message: String::new() })
+            .context("Failed to sign SSH key")
+            .unwrap_err();
+        assert_eq!(exit_code(&wrapped), 11);
+        assert_eq!(exit_code(&anyhow::anyhow!("something else")), 1);
+    }
+}
@@ -2,14 +2,45 @@ use clap::Parser;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+/// Profile used unless another one is selected
+pub const DEFAULT_PROFILE: &str = "default";
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
+    /// Name of the selected `[profile.<name>]` section
+    #[serde(skip)]
+    pub profile: String,
     pub key_path: PathBuf,
     pub key_validity: String,
+    pub key_type: String,
+    pub issue_method: String,
+    pub renew_margin: String,
+    pub add_to_agent: bool,
     pub pkce_client_id: String,
+    pub login_method: String,
+    pub redirect_ports: String,
+    pub login_timeout: String,
+    pub token_storage: String,
+    pub token_encryption: String,
     pub issuer_url: String,
+    pub discovery_ttl: String,
     pub keys_url: String,
     pub sign_url: String,
+    pub http_retries: u32,
+    pub retry_backoff: String,
+    pub connect_timeout: String,
+    pub read_timeout: String,
+    pub proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    pub ca_bundles: Vec<PathBuf>,
+    pub client_certificate: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub service_token_url: String,
+    pub api_key_file: Option<PathBuf>,
+    pub api_key_command: Option<String>,
+    pub username: Option<String>,
+    pub jump_host: String,
+    pub clusters: Vec<String>,
 }
 
 #[derive(Parser, Debug, Deserialize, Serialize)]
@@ -20,31 +51,175 @@ pub struct ConfigCliOverride {
     #[arg(long, global = true)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub key_validity: Option<String>,
+    #[arg(long, global = true, help = "Type of locally generated keys: ed25519, ecdsa-p256, ecdsa-p384, rsa-3072 or rsa-4096")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_type: Option<String>,
+    #[arg(long, global = true, help = "How `ensure` issues certificates: sign (local key pair) or gen (server generated)")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issue_method: Option<String>,
+    #[arg(long, global = true, help = "Renew certificates that expire within this time")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renew_margin: Option<String>,
+    #[arg(long, global = true, num_args = 0..=1, default_missing_value = "true", help = "Add issued certificates to the running ssh-agent")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub add_to_agent: Option<bool>,
     #[arg(long, global = true)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub pkce_client_id: Option<String>,
+    #[arg(long, global = true, help = "Interactive login method: auto, browser or device")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub login_method: Option<String>,
+    #[arg(long, global = true, help = "Local port, port range (8765-8775) or 0 (any) for the browser login redirect")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_ports: Option<String>,
+    #[arg(long, global = true, help = "How long to wait for the browser login to complete")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub login_timeout: Option<String>,
+    #[arg(long, global = true, help = "Where OIDC tokens are kept: file, keyring, encrypted or memory")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_storage: Option<String>,
+    #[arg(long, global = true, help = "Key of the encrypted token storage: passphrase (CSCS_KEY_PASSPHRASE or prompt) or ssh-agent")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_encryption: Option<String>,
     #[arg(long, global = true)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub issuer_url: Option<String>,
+    #[arg(long, global = true, help = "How long the OpenID provider metadata and keys are cached")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discovery_ttl: Option<String>,
     #[arg(long, global = true)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub keys_url: Option<String>,
     #[arg(long, global = true)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sign_url: Option<String>,
+    #[arg(long, global = true, help = "How often to retry failed HTTP requests")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_retries: Option<u32>,
+    #[arg(long, global = true, help = "Initial delay between retries, doubled on every attempt")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_backoff: Option<String>,
+    #[arg(long, global = true, help = "Timeout for establishing a connection")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<String>,
+    #[arg(long, global = true, help = "Timeout for receiving a response")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_timeout: Option<String>,
+    #[arg(long, global = true, help = "Proxy for all requests (http://, https:// or socks5:// URL) [default: HTTPS_PROXY]")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    #[arg(long, global = true, help = "Comma-separated hosts to reach without the proxy [default: NO_PROXY]")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_proxy: Option<String>,
+    #[arg(long = "ca-bundle", global = true, value_name = "PATH", help = "Additional trusted root certificates in PEM format (repeatable)")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_bundles: Option<Vec<PathBuf>>,
+    #[arg(long, global = true, value_name = "PATH", help = "Client certificate (PEM) for mutual TLS")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_certificate: Option<PathBuf>,
+    #[arg(long, global = true, value_name = "PATH", help = "PKCS#8 private key (PEM) of the client certificate")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<PathBuf>,
+    #[arg(long, global = true, help = "Token endpoint of the service-account API")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_token_url: Option<String>,
+    #[arg(long, global = true, help = "Read the service-account API key from this file (CSCS_API_KEY takes precedence)")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key_file: Option<PathBuf>,
+    #[arg(long, global = true, help = "Shell command printing the service-account API key")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key_command: Option<String>,
+    #[arg(long, global = true, help = "CSCS username used in the generated ssh config")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[arg(long, global = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jump_host: Option<String>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            profile: DEFAULT_PROFILE.to_string(),
             key_path: dirs::home_dir()
                 .expect("Could not determine home directory")
                 .join(".ssh/cscs-key"),
             key_validity: "1min".to_string(),
+            key_type: "ed25519".to_string(),
+            issue_method: "sign".to_string(),
+            renew_margin: "30s".to_string(),
+            add_to_agent: false,
             pkce_client_id: "authx-cli".to_string(),
+            login_method: "auto".to_string(),
+            redirect_ports: "8765".to_string(),
+            login_timeout: "5min".to_string(),
+            token_storage: "file".to_string(),
+            token_encryption: "passphrase".to_string(),
             issuer_url: "https://auth.cscs.ch/auth/realms/cscs".to_string(),
+            discovery_ttl: "1d".to_string(),
             keys_url: "https://api-ssh-service.hpc-ssh.svc.cscs.ch/api/v1/ssh-keys".to_string(),
             sign_url: "https://api-ssh-service.hpc-ssh.svc.cscs.ch/api/v1/ssh-keys/sign".to_string(),
+            http_retries: 3,
+            retry_backoff: "1s".to_string(),
+            connect_timeout: "10s".to_string(),
+            read_timeout: "30s".to_string(),
+            proxy: None,
+            no_proxy: None,
+            ca_bundles: Vec::new(),
+            client_certificate: None,
+            client_key: None,
+            service_token_url: "https://api-service-account.hpc-user.tds.cscs.ch/api/v1/auth/token".to_string(),
+            api_key_file: None,
+            api_key_command: None,
+            username: None,
+            jump_host: "ela.cscs.ch".to_string(),
+            clusters: vec![
+                "daint.alps.cscs.ch".to_string(),
+                "eiger.alps.cscs.ch".to_string(),
+                "clariden.alps.cscs.ch".to_string(),
+                "santis.alps.cscs.ch".to_string(),
+            ],
+        }
+    }
+}
+
+impl Config {
+    /// Defaults of a profile, every profile but the default one keeps its key at a separate path
+    pub fn defaults_for(profile: &str) -> Self {
+        let mut config = Self::default();
+        if profile != DEFAULT_PROFILE {
+            config.key_path.set_file_name(format!("cscs-key-{}", profile));
         }
+        config.profile = profile.to_string();
+        config
+    }
+
+    /// Command line arguments selecting this profile and key path, for commands run by ssh or systemd.
+    /// The profile is always passed, `default_profile` in the configuration file may change later.
+    pub fn selection_args(&self) -> Vec<String> {
+        let mut args = vec!["--profile".to_string(), self.profile.clone()];
+        if self.key_path != Self::defaults_for(&self.profile).key_path {
+            args.push("--key-path".to_string());
+            args.push(self.key_path.display().to_string());
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selection_args_always_name_the_profile() {
+        assert_eq!(Config::defaults_for(DEFAULT_PROFILE).selection_args(), ["--profile", "default"]);
+        assert_eq!(Config::defaults_for("dev").selection_args(), ["--profile", "dev"]);
+    }
+
+    #[test]
+    fn selection_args_include_a_custom_key_path() {
+        let mut config = Config::defaults_for("dev");
+        config.key_path = PathBuf::from("/keys/cscs");
+        assert_eq!(config.selection_args(), ["--profile", "dev", "--key-path", "/keys/cscs"]);
     }
 }
@@ -0,0 +1,149 @@
+use clap::Args;
+use std::path::PathBuf;
+use anyhow::Context;
+use log::{info, debug};
+
+use crate::config::{Config, DEFAULT_PROFILE};
+use crate::files;
+use crate::http::HttpClient;
+use crate::renewal;
+use crate::ssh::{ensure_key, KeyFiles};
+
+#[derive(Args, Debug)]
+pub struct InstallServiceArgs {
+    #[arg(long, value_name = "DIR", help = "Directory for the unit files [default: ~/.config/systemd/user]")]
+    output: Option<PathBuf>,
+    #[arg(long, help = "Print the unit files instead of writing them")]
+    dry_run: bool,
+}
+
+/// Keep the certificate at `Config::key_path` valid, renewing it before it expires
+pub fn run_daemon(config: &Config) -> anyhow::Result<()> {
+    debug!("ssh-key daemon subcommand");
+    debug!("{:?}", config);
+
+    let margin = renewal::renew_margin(config)?;
+    let key_files = KeyFiles::configured(config)?;
+    let http_client = HttpClient::new(config)?;
+
+    // The first round renews right away if needed, later ones once the certificate is due
+    renewal::keep_renewed(margin, None, || {
+        ensure_key(config, &http_client)?;
+        key_files.read_certificate()?
+            .with_context(|| format!("No certificate at {}", key_files.certificate.display()))
+    })
+}
+
+/// Write a systemd user service running `cscs-key daemon` and a timer restarting it if it stopped
+pub fn install_service(config: &Config, args: &InstallServiceArgs) -> anyhow::Result<()> {
+    debug!("ssh-key install-service subcommand");
+    debug!("{:?}", config);
+
+    let output = match &args.output {
+        Some(output) => output.clone(),
+        None => dirs::config_dir()
+            .context("Could not determine configuration directory")?
+            .join("systemd")
+            .join("user"),
+    };
+
+    // One service per profile, so that several profiles can be kept valid
+    let service_name = if config.profile == DEFAULT_PROFILE {
+        "cscs-key".to_string()
+    } else {
+        format!("cscs-key-{}", config.profile)
+    };
+
+    let exe = std::env::current_exe().context("Could not determine the cscs-key executable")?;
+    let mut words = vec![exe.display().to_string(), "daemon".to_string()];
+    words.extend(config.selection_args());
+    let command = exec_start(&words);
+
+    let service = format!(
+        "[Unit]\n\
+        Description=Keep the CSCS SSH certificate valid\n\
+        After=network-online.target\n\
+        \n\
+        [Service]\n\
+        Type=simple\n\
+        ExecStart={}\n\
+        Restart=on-failure\n\
+        RestartSec=1min\n\
+        \n\
+        [Install]\n\
+        WantedBy=default.target\n",
+        command,
+    );
+    let timer = format!(
+        "[Unit]\n\
+        Description=Start the CSCS SSH certificate renewal daemon\n\
+        \n\
+        [Timer]\n\
+        OnStartupSec=1min\n\
+        OnUnitInactiveSec=15min\n\
+        Unit={}.service\n\
+        \n\
+        [Install]\n\
+        WantedBy=timers.target\n",
+        service_name,
+    );
+
+    let units = [
+        (output.join(format!("{}.service", service_name)), service),
+        (output.join(format!("{}.timer", service_name)), timer),
+    ];
+
+    if args.dry_run {
+        for (path, content) in &units {
+            println!("# {}\n{}", path.display(), content);
+        }
+        return Ok(());
+    }
+
+    for (path, content) in &units {
+        info!("Writing {}", path.display());
+        files::write_atomic(path, content.as_bytes(), files::PUBLIC)?;
+        println!("Unit written to {}", path.display());
+    }
+
+    println!("Enable the renewal daemon with:");
+    println!("    systemctl --user daemon-reload");
+    println!("    systemctl --user enable --now {}.timer {}.service", service_name, service_name);
+    println!("The daemon cannot log in interactively, run `cscs-key ensure` once to log in first.");
+
+    Ok(())
+}
+
+// Command line in systemd syntax: words with spaces or quotes are double-quoted with C escapes,
+// and % specifiers and $ variables are escaped everywhere
+fn exec_start(words: &[String]) -> String {
+    words.iter()
+        .map(|word| {
+            let word = word.replace('%', "%%").replace('$', "$$");
+            let safe = !word.is_empty()
+                && word.chars().all(|c| c.is_ascii_alphanumeric() || "/._+-=:,@%$".contains(c));
+            if safe {
+                word
+            } else {
+                format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_start_quotes_and_escapes_words() {
+        let words = |words: &[&str]| words.iter().map(|word| word.to_string()).collect::<Vec<_>>();
+        assert_eq!(exec_start(&words(&["/usr/bin/cscs-key", "daemon", "--profile", "dev"])),
+            "/usr/bin/cscs-key daemon --profile dev");
+        assert_eq!(exec_start(&words(&["/home/a b/cscs-key", "daemon"])),
+            r#""/home/a b/cscs-key" daemon"#);
+        assert_eq!(exec_start(&words(&[r#"/a "b"\c/100%$x"#])),
+            r#""/a \"b\"\\c/100%%$$x""#);
+    }
+}
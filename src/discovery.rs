@@ -0,0 +1,151 @@
+use anyhow::{anyhow, bail, Context};
+use chrono::Utc;
+use log::{info, debug};
+use reqwest::StatusCode;
+use reqwest::header::{ACCEPT, ETAG, IF_NONE_MATCH};
+use serde::{Deserialize, Serialize};
+
+use crate::api::check_response;
+use crate::config::Config;
+use crate::http::HttpClient;
+use crate::state::{DiscoveryCache, DiscoveryDocument, ProviderDocuments};
+
+use openidconnect::core::{
+    CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClientAuthMethod, CoreGrantType,
+    CoreJsonWebKey, CoreJsonWebKeySet, CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm, CoreResponseMode, CoreResponseType, CoreSubjectIdentifierType,
+};
+use openidconnect::{
+    AdditionalProviderMetadata, DeviceAuthorizationUrl, EndSessionUrl, IssuerUrl, ProviderMetadata, RevocationUrl,
+};
+
+const CONFIG_URL_SUFFIX: &str = ".well-known/openid-configuration";
+
+// Provider metadata fields not covered by the core OIDC discovery document
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ExtraProviderMetadata {
+    pub device_authorization_endpoint: Option<DeviceAuthorizationUrl>,
+    /// RFC 7009 token revocation
+    pub revocation_endpoint: Option<RevocationUrl>,
+    /// OpenID Connect RP-initiated logout
+    pub end_session_endpoint: Option<EndSessionUrl>,
+}
+impl AdditionalProviderMetadata for ExtraProviderMetadata {}
+
+pub type ExtendedProviderMetadata = ProviderMetadata<
+    ExtraProviderMetadata,
+    CoreAuthDisplay,
+    CoreClientAuthMethod,
+    CoreClaimName,
+    CoreClaimType,
+    CoreGrantType,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm,
+    CoreJsonWebKey,
+    CoreResponseMode,
+    CoreResponseType,
+    CoreSubjectIdentifierType,
+>;
+
+/// Provider metadata and signing keys of the configured issuer. Both are cached for
+/// `Config::discovery_ttl` and then revalidated with their ETag; `force_refresh` skips the TTL,
+/// e.g. after the provider rotated its signing keys.
+pub fn discover(config: &Config, http_client: &HttpClient, force_refresh: bool) -> anyhow::Result<ExtendedProviderMetadata> {
+    let ttl = duration_str::parse(&config.discovery_ttl)
+        .map_err(|e| anyhow!("Invalid discovery TTL '{}': {}", config.discovery_ttl, e))?;
+    let issuer_url = IssuerUrl::new(config.issuer_url.clone())?;
+
+    let cached = DiscoveryCache::load()
+        .unwrap_or_else(|e| {
+            info!("Ignoring unreadable discovery cache: {}", e);
+            DiscoveryCache::default()
+        })
+        .providers
+        .remove(&config.issuer_url);
+
+    if let Some(cached) = &cached
+        && !force_refresh
+        && Utc::now() - cached.fetched_at < chrono::Duration::from_std(ttl)?
+    {
+        debug!("Using cached discovery document of {}", config.issuer_url);
+        return provider_metadata(&issuer_url, cached);
+    }
+
+    let documents = fetch(config, http_client, &issuer_url, cached)
+        .with_context(|| format!("Failed to discover OpenID provider at {}", config.issuer_url))?;
+    let metadata = provider_metadata(&issuer_url, &documents)?;
+
+    if let Err(e) = DiscoveryCache::update(|cache| cache.providers.insert(config.issuer_url.clone(), documents)) {
+        info!("Failed to save the discovery cache: {}", e);
+    }
+    Ok(metadata)
+}
+
+// Fetch the discovery document and the JWKS, reusing cached copies the server reports unchanged
+fn fetch(
+    config: &Config,
+    http_client: &HttpClient,
+    issuer_url: &IssuerUrl,
+    cached: Option<ProviderDocuments>,
+) -> anyhow::Result<ProviderDocuments> {
+    let (cached_metadata, cached_jwks) = match cached {
+        Some(cached) => (Some(cached.metadata), Some(cached.jwks)),
+        None => (None, None),
+    };
+
+    let discovery_url = issuer_url.join(CONFIG_URL_SUFFIX)?;
+    let metadata = fetch_document(http_client, discovery_url.as_str(), cached_metadata)?;
+
+    let jwks_uri = metadata.content.get("jwks_uri")
+        .and_then(|uri| uri.as_str())
+        .with_context(|| format!("No jwks_uri in the discovery document of {}", config.issuer_url))?
+        .to_string();
+    // A changed key set URL invalidates the cached keys
+    let cached_jwks = cached_jwks.filter(|jwks| jwks.url == jwks_uri);
+    let jwks = fetch_document(http_client, &jwks_uri, cached_jwks)?;
+
+    Ok(ProviderDocuments { fetched_at: Utc::now(), metadata, jwks })
+}
+
+fn fetch_document(
+    http_client: &HttpClient,
+    url: &str,
+    cached: Option<DiscoveryDocument>,
+) -> anyhow::Result<DiscoveryDocument> {
+    let mut request = http_client.provider_get(url).header(ACCEPT, "application/json");
+    if let Some(etag) = cached.as_ref().and_then(|cached| cached.etag.as_ref()) {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+
+    let response = http_client.send(request, true)?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        if let Some(cached) = cached {
+            debug!("{} not modified", url);
+            return Ok(cached);
+        }
+        bail!("Unexpected 304 Not Modified from {}", url);
+    }
+
+    info!("Fetching {}", url);
+    let response = check_response(response)?;
+    let etag = response.headers().get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string);
+    let content = response.json()
+        .with_context(|| format!("Invalid JSON from {}", url))?;
+
+    Ok(DiscoveryDocument { url: url.to_string(), etag, content })
+}
+
+fn provider_metadata(issuer_url: &IssuerUrl, documents: &ProviderDocuments) -> anyhow::Result<ExtendedProviderMetadata> {
+    let metadata: ExtendedProviderMetadata = serde_json::from_value(documents.metadata.content.clone())
+        .context("Invalid OpenID provider metadata")?;
+    if metadata.issuer() != issuer_url {
+        bail!("Unexpected issuer URI '{}' in the discovery document (expected '{}')",
+            metadata.issuer().as_str(), issuer_url.as_str());
+    }
+    let jwks: CoreJsonWebKeySet = serde_json::from_value(documents.jwks.content.clone())
+        .context("Invalid JSON Web Key Set")?;
+
+    Ok(metadata.set_jwks(jwks))
+}
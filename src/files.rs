@@ -0,0 +1,178 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context};
+use log::{info, debug, warn};
+use ssh_key::rand_core::{OsRng, RngCore};
+
+/// Mode of files holding tokens or private keys
+pub const PRIVATE: u32 = 0o600;
+/// Mode of public keys, certificates and other files meant to be read by others
+pub const PUBLIC: u32 = 0o644;
+
+/// Exclusive advisory lock on `<path>.lock`, released when dropped
+pub struct FileLock {
+    _file: File,
+}
+
+/// Wait for the advisory lock guarding updates of `path`
+pub fn lock(path: &Path) -> anyhow::Result<FileLock> {
+    let lock_path = PathBuf::from(format!("{}.lock", path.display()));
+    if let Some(parent) = lock_path.parent() {
+        create_dir(parent)?;
+    }
+    let file = open_new_file(&lock_path, fs::OpenOptions::new().create(true).truncate(false).write(true), PRIVATE)
+        .with_context(|| format!("Failed to open lock file {}", lock_path.display()))?;
+    debug!("Acquiring lock {}", lock_path.display());
+    file.lock().with_context(|| format!("Failed to lock {}", lock_path.display()))?;
+    Ok(FileLock { _file: file })
+}
+
+/// Create a directory and its missing parents, accessible only by the owner
+pub fn create_dir(path: &Path) -> anyhow::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(path)
+        .with_context(|| format!("Failed to create directory {}", path.display()))
+}
+
+/// Replace `path` atomically: readers see either the old or the new content, never a partial file
+pub fn write_atomic(path: &Path, contents: &[u8], mode: u32) -> anyhow::Result<()> {
+    let temp_path = write_temp(path, contents, mode)?;
+    fs::rename(&temp_path, path).map_err(|e| {
+        let _ = fs::remove_file(&temp_path);
+        anyhow!("Failed to replace {}: {}", path.display(), e)
+    })
+}
+
+/// Replace two files that belong together, such as a key and its certificate. If the second
+/// one cannot be replaced, the first one is restored.
+pub fn write_pair(first: (&Path, &[u8], u32), second: (&Path, &[u8], u32)) -> anyhow::Result<()> {
+    let first_temp = write_temp(first.0, first.1, first.2)?;
+    let second_temp = match write_temp(second.0, second.1, second.2) {
+        Ok(temp_path) => temp_path,
+        Err(e) => {
+            let _ = fs::remove_file(&first_temp);
+            return Err(e);
+        }
+    };
+
+    let previous = fs::read(first.0).ok();
+    if let Err(e) = fs::rename(&first_temp, first.0) {
+        let _ = fs::remove_file(&first_temp);
+        let _ = fs::remove_file(&second_temp);
+        return Err(anyhow!("Failed to replace {}: {}", first.0.display(), e));
+    }
+    if let Err(e) = fs::rename(&second_temp, second.0) {
+        let _ = fs::remove_file(&second_temp);
+        warn!("Failed to replace {}, restoring {}", second.0.display(), first.0.display());
+        let restored = match &previous {
+            Some(previous) => write_atomic(first.0, previous, first.2),
+            None => fs::remove_file(first.0).map_err(anyhow::Error::from),
+        };
+        if let Err(restore_error) = restored {
+            warn!("Failed to restore {}: {}", first.0.display(), restore_error);
+        }
+        return Err(anyhow!("Failed to replace {}: {}", second.0.display(), e));
+    }
+    Ok(())
+}
+
+// Write the contents to a new file next to `path`, created with `mode` and flushed to disk
+fn write_temp(path: &Path, contents: &[u8], mode: u32) -> anyhow::Result<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    create_dir(parent)?;
+    let file_name = path.file_name()
+        .with_context(|| format!("Invalid file path {}", path.display()))?;
+    let temp_path = parent.join(format!(".{}.{}.{:08x}.tmp", file_name.to_string_lossy(), std::process::id(), OsRng.next_u32()));
+
+    info!("Writing {}", path.display());
+    let result = open_new_file(&temp_path, fs::OpenOptions::new().write(true).create_new(true), mode)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(anyhow!("Failed to write {}: {}", temp_path.display(), e));
+    }
+    Ok(temp_path)
+}
+
+fn open_new_file(path: &Path, options: &mut fs::OpenOptions, mode: u32) -> std::io::Result<File> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    options.open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cscs-key-files-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn temp_files(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(".tmp"))
+            .count()
+    }
+
+    #[test]
+    fn write_pair_replaces_both_files() {
+        let dir = test_dir("pair");
+        let (key, cert) = (dir.join("key"), dir.join("key-cert.pub"));
+        fs::write(&key, "old key").unwrap();
+
+        write_pair((&key, b"new key", PRIVATE), (&cert, b"new cert", PUBLIC)).unwrap();
+        assert_eq!(fs::read_to_string(&key).unwrap(), "new key");
+        assert_eq!(fs::read_to_string(&cert).unwrap(), "new cert");
+        assert_eq!(temp_files(&dir), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_pair_restores_the_first_file_if_the_second_fails() {
+        let dir = test_dir("rollback");
+        let (key, cert) = (dir.join("key"), dir.join("key-cert.pub"));
+        fs::write(&key, "old key").unwrap();
+        // A non-empty directory cannot be replaced by a file
+        create_dir(&cert).unwrap();
+        fs::write(cert.join("blocker"), "").unwrap();
+
+        assert!(write_pair((&key, b"new key", PRIVATE), (&cert, b"new cert", PUBLIC)).is_err());
+        assert_eq!(fs::read_to_string(&key).unwrap(), "old key");
+        assert_eq!(temp_files(&dir), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_pair_removes_a_new_first_file_if_the_second_fails() {
+        let dir = test_dir("rollback-new");
+        let (key, cert) = (dir.join("key"), dir.join("key-cert.pub"));
+        create_dir(&cert).unwrap();
+        fs::write(cert.join("blocker"), "").unwrap();
+
+        assert!(write_pair((&key, b"new key", PRIVATE), (&cert, b"new cert", PUBLIC)).is_err());
+        assert!(!key.exists());
+        assert_eq!(temp_files(&dir), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,334 @@
+use std::fs;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, Utc};
+use log::{info, debug};
+use openidconnect::{http, HttpClientError, HttpRequest, HttpResponse, SyncHttpClient};
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::redirect::Policy;
+use reqwest::{Certificate, Identity, IntoUrl, NoProxy, Proxy, StatusCode};
+use ssh_key::rand_core::{OsRng, RngCore};
+
+use crate::config::Config;
+
+const USER_AGENT: &str = concat!("cscs-key/", env!("CARGO_PKG_VERSION"));
+
+/// Upper bound for the exponential backoff between attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Upper bound for a server supplied Retry-After delay
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// HTTP client for the identity provider and the SSH service, retrying transient failures
+pub struct HttpClient {
+    /// Client for the SSH service, following redirects
+    client: reqwest::blocking::Client,
+    /// Client for the identity provider, which never follows redirects (recommended for OIDC security)
+    provider_client: reqwest::blocking::Client,
+    retries: u32,
+    backoff: Duration,
+}
+
+// Whether a failed attempt may be repeated
+enum Retry {
+    No,
+    /// The server did not process the request (connection refused, 429, 503)
+    Safe(Option<Duration>),
+    /// The server may have processed the request
+    IfIdempotent,
+}
+
+impl HttpClient {
+    /// Build the client from the network settings in `config`
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: build_client(config, Policy::default())?,
+            provider_client: build_client(config, Policy::none())?,
+            retries: config.http_retries,
+            backoff: parse_duration("retry backoff", &config.retry_backoff)?,
+        })
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    pub fn delete(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.client.delete(url)
+    }
+
+    pub fn provider_get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.provider_client.get(url)
+    }
+
+    pub fn provider_post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.provider_client.post(url)
+    }
+
+    /// Send a request, retrying transient failures. Requests that are not idempotent
+    /// are only repeated if the server cannot have processed them.
+    pub fn send(&self, request: RequestBuilder, idempotent: bool) -> reqwest::Result<Response> {
+        // Streaming bodies cannot be sent twice
+        if request.try_clone().is_none() {
+            return request.send();
+        }
+        self.with_retries(
+            idempotent,
+            || request.try_clone().expect("request is cloneable").send(),
+            |result| match result {
+                Ok(response) => classify_status(response.status(), response.headers()),
+                Err(e) => classify_error(e),
+            },
+        )
+    }
+
+    fn with_retries<T, E: std::error::Error>(
+        &self,
+        idempotent: bool,
+        send: impl Fn() -> Result<T, E>,
+        classify: impl Fn(&Result<T, E>) -> Retry,
+    ) -> Result<T, E> {
+        let mut attempt = 0;
+        loop {
+            let result = send();
+            let retry_after = match classify(&result) {
+                Retry::Safe(retry_after) => retry_after,
+                Retry::IfIdempotent if idempotent => None,
+                _ => return result,
+            };
+            if attempt >= self.retries {
+                return result;
+            }
+
+            let delay = match retry_after {
+                Some(retry_after) => retry_after.min(MAX_RETRY_AFTER),
+                None => self.backoff_delay(attempt),
+            };
+            match &result {
+                Ok(_) => info!("Transient server error, retrying in {} ms", delay.as_millis()),
+                Err(e) => info!("Request failed ({}), retrying in {} ms", error_chain(e), delay.as_millis()),
+            }
+            attempt += 1;
+            std::thread::sleep(delay);
+        }
+    }
+
+    // Exponential backoff with jitter, so that many clients do not retry in lockstep
+    fn backoff_delay(&self, attempt: u32) -> Duration {
+        let delay = self.backoff.saturating_mul(2u32.saturating_pow(attempt)).min(MAX_BACKOFF);
+        let jitter = f64::from(OsRng.next_u32()) / f64::from(u32::MAX);
+        delay.mul_f64(0.5 + jitter / 2.0)
+    }
+}
+
+// The OIDC library sends discovery, token and device requests through this
+impl SyncHttpClient for HttpClient {
+    type Error = HttpClientError<reqwest::Error>;
+
+    fn call(&self, request: HttpRequest) -> Result<HttpResponse, Self::Error> {
+        // Token requests are POSTs, authorization codes and rotated refresh tokens are single use
+        let idempotent = request.method() != http::Method::POST;
+        self.with_retries(
+            idempotent,
+            || self.provider_client.call(copy_request(&request)),
+            |result| match result {
+                Ok(response) => classify_status(response.status(), response.headers()),
+                Err(HttpClientError::Reqwest(e)) => classify_error(e),
+                Err(_) => Retry::No,
+            },
+        )
+    }
+}
+
+// Build a client with the proxy, CA and client certificate settings in `config`
+fn build_client(config: &Config, redirect: Policy) -> anyhow::Result<reqwest::blocking::Client> {
+    let mut builder = reqwest::blocking::Client::builder()
+        .redirect(redirect)
+        .user_agent(USER_AGENT)
+        .connect_timeout(parse_duration("connect timeout", &config.connect_timeout)?)
+        // The blocking client has a single timeout covering the whole request
+        .timeout(parse_duration("read timeout", &config.read_timeout)?);
+
+    // Without an explicit proxy, reqwest uses HTTPS_PROXY, ALL_PROXY and NO_PROXY
+    if let Some(proxy) = &config.proxy {
+        let no_proxy = match &config.no_proxy {
+            Some(no_proxy) => NoProxy::from_string(no_proxy),
+            None => NoProxy::from_env(),
+        };
+        let proxy = Proxy::all(proxy)
+            .with_context(|| format!("Invalid proxy '{}'", proxy))?
+            .no_proxy(no_proxy);
+        builder = builder.proxy(proxy);
+    }
+
+    // Proxies doing TLS inspection present certificates signed by their own CA
+    for path in &config.ca_bundles {
+        let pem = fs::read(path)
+            .with_context(|| format!("Failed to read CA bundle {}", path.display()))?;
+        let certificates = Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Invalid CA bundle {}", path.display()))?;
+        if certificates.is_empty() {
+            bail!("No PEM certificates found in CA bundle {}", path.display());
+        }
+        debug!("Adding {} root certificates from {}", certificates.len(), path.display());
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    match (&config.client_certificate, &config.client_key) {
+        (Some(certificate), Some(key)) => {
+            let certificate_pem = fs::read(certificate)
+                .with_context(|| format!("Failed to read client certificate {}", certificate.display()))?;
+            let key_pem = fs::read(key)
+                .with_context(|| format!("Failed to read client key {}", key.display()))?;
+            let identity = Identity::from_pkcs8_pem(&certificate_pem, &key_pem)
+                .context("Invalid client certificate or key, the key must be in PKCS#8 format")?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => bail!("Both a client certificate and a client key are required for mutual TLS"),
+    }
+
+    builder.build().context("Failed to set up the HTTP client")
+}
+
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    message
+}
+
+fn copy_request(request: &HttpRequest) -> HttpRequest {
+    let mut copy = http::Request::new(request.body().clone());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    copy
+}
+
+fn classify_status(status: StatusCode, headers: &HeaderMap) -> Retry {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => Retry::Safe(retry_after(headers)),
+        StatusCode::INTERNAL_SERVER_ERROR | StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT => {
+            Retry::IfIdempotent
+        }
+        _ => Retry::No,
+    }
+}
+
+fn classify_error(error: &reqwest::Error) -> Retry {
+    if error.is_connect() {
+        // Nothing was sent
+        Retry::Safe(None)
+    } else if error.is_timeout() || error.is_request() || error.is_body() {
+        Retry::IfIdempotent
+    } else {
+        Retry::No
+    }
+}
+
+fn parse_duration(name: &str, value: &str) -> anyhow::Result<Duration> {
+    duration_str::parse(value).map_err(|e| anyhow!("Invalid {} '{}': {}", name, value, e))
+}
+
+/// Delay requested by a Retry-After header, in seconds or as an HTTP date
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    debug!("Retry-After date {}", date);
+    Some((date.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use reqwest::header::HeaderValue;
+
+    fn client(retries: u32, backoff: Duration) -> HttpClient {
+        HttpClient { client: reqwest::blocking::Client::new(), provider_client: reqwest::blocking::Client::new(), retries, backoff }
+    }
+
+    fn headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    #[test]
+    fn retry_after_accepts_seconds_and_dates() {
+        assert_eq!(retry_after(&headers("120")), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(&headers(" 5 ")), Some(Duration::from_secs(5)));
+
+        let date = (Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let delay = retry_after(&headers(&date)).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60), "{:?}", delay);
+        // Dates in the past mean no delay
+        assert_eq!(retry_after(&headers("Wed, 21 Oct 2015 07:28:00 GMT")), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_ignores_missing_and_invalid_values() {
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+        assert_eq!(retry_after(&headers("soon")), None);
+        assert_eq!(retry_after(&headers("-1")), None);
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_limit() {
+        let client = client(10, Duration::from_secs(1));
+        for attempt in 0..4 {
+            let full = Duration::from_secs(1 << attempt);
+            let delay = client.backoff_delay(attempt);
+            assert!(delay >= full / 2 && delay <= full, "attempt {}: {:?}", attempt, delay);
+        }
+        assert!(client.backoff_delay(10) <= MAX_BACKOFF);
+        assert!(client.backoff_delay(u32::MAX) <= MAX_BACKOFF);
+    }
+
+    #[test]
+    fn status_codes_are_classified_for_retries() {
+        assert!(matches!(classify_status(StatusCode::TOO_MANY_REQUESTS, &headers("3")), Retry::Safe(Some(delay)) if delay == Duration::from_secs(3)));
+        assert!(matches!(classify_status(StatusCode::SERVICE_UNAVAILABLE, &HeaderMap::new()), Retry::Safe(None)));
+        assert!(matches!(classify_status(StatusCode::BAD_GATEWAY, &HeaderMap::new()), Retry::IfIdempotent));
+        assert!(matches!(classify_status(StatusCode::UNAUTHORIZED, &HeaderMap::new()), Retry::No));
+        assert!(matches!(classify_status(StatusCode::OK, &HeaderMap::new()), Retry::No));
+    }
+
+    // Count the attempts of a request that always fails with the given classification
+    fn attempts(client: &HttpClient, idempotent: bool, retry: fn() -> Retry) -> u32 {
+        let attempts = Cell::new(0);
+        let _ = client.with_retries(
+            idempotent,
+            || {
+                attempts.set(attempts.get() + 1);
+                Err::<(), _>(std::fmt::Error)
+            },
+            |_| retry(),
+        );
+        attempts.get()
+    }
+
+    #[test]
+    fn retries_depend_on_idempotency() {
+        let retrying = client(2, Duration::ZERO);
+        assert_eq!(attempts(&retrying, false, || Retry::Safe(Some(Duration::ZERO))), 3);
+        assert_eq!(attempts(&retrying, true, || Retry::IfIdempotent), 3);
+        assert_eq!(attempts(&retrying, false, || Retry::IfIdempotent), 1);
+        assert_eq!(attempts(&retrying, true, || Retry::No), 1);
+        assert_eq!(attempts(&client(0, Duration::ZERO), true, || Retry::Safe(None)), 1);
+    }
+}
@@ -0,0 +1,286 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, bail, Context};
+use log::{info, debug};
+use url::Url;
+
+/// Loopback listener receiving the OAuth redirect after the browser login
+pub struct RedirectListener {
+    listeners: Vec<TcpListener>,
+    port: u16,
+}
+
+enum Request {
+    /// Authorization code of the login this listener waits for
+    Callback(String),
+    Failed(anyhow::Error),
+    Ignored,
+}
+
+impl RedirectListener {
+    /// Bind to the first free port of `ports`, which is a single port ("8765"),
+    /// an inclusive range ("8765-8775") or "0" for an ephemeral port.
+    /// Listens on the IPv4 and, where available, the IPv6 loopback address.
+    pub fn bind(ports: &str) -> anyhow::Result<Self> {
+        let (first, last) = parse_port_range(ports)?;
+
+        for port in first..=last {
+            match Self::bind_port(port) {
+                Ok(listener) => return Ok(listener),
+                Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                    info!("Redirect port {} is in use", port);
+                }
+                Err(e) => return Err(e).context("Failed to bind the login redirect listener"),
+            }
+        }
+
+        bail!("All login redirect ports ({}) are in use. Configure a different range with --redirect-ports.", ports);
+    }
+
+    fn bind_port(mut port: u16) -> std::io::Result<Self> {
+        let mut listeners = Vec::new();
+        match TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port))) {
+            Ok(listener) => {
+                port = listener.local_addr()?.port();
+                listeners.push(listener);
+            }
+            // No IPv4 loopback on this machine, IPv6 only
+            Err(e) if e.kind() == ErrorKind::AddrNotAvailable => {}
+            Err(e) => return Err(e),
+        }
+
+        // Browsers may resolve localhost to ::1 first, so listen there as well.
+        // With port 0 and no IPv4 loopback, the port is only known once bound here.
+        match TcpListener::bind(SocketAddr::from((Ipv6Addr::LOCALHOST, port))) {
+            Ok(listener) => {
+                port = listener.local_addr()?.port();
+                listeners.push(listener);
+            }
+            Err(e) if listeners.is_empty() => return Err(e),
+            Err(e) => debug!("Not listening on IPv6 loopback: {}", e),
+        }
+
+        for listener in &listeners {
+            listener.set_nonblocking(true)?;
+        }
+        info!("Listening for the login redirect on port {}", port);
+
+        Ok(Self { listeners, port })
+    }
+
+    pub fn redirect_uri(&self) -> String {
+        format!("http://localhost:{}", self.port)
+    }
+
+    /// Serve requests until the identity provider redirects back with a code or an error.
+    /// Redirects whose `state` does not match belong to another login and are ignored.
+    pub fn wait_for_callback(&self, timeout: Duration, state: &str) -> anyhow::Result<String> {
+        let deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline {
+            let mut accepted = false;
+            for listener in &self.listeners {
+                let stream = match listener.accept() {
+                    Ok((stream, peer)) => {
+                        debug!("Connection from {}", peer);
+                        stream
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                accepted = true;
+
+                match self.handle(stream, state) {
+                    Ok(Request::Callback(code)) => return Ok(code),
+                    Ok(Request::Failed(e)) => return Err(e),
+                    Ok(Request::Ignored) => {}
+                    Err(e) => debug!("Failed to handle request: {}", e),
+                }
+            }
+
+            if !accepted {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+
+        bail!("Timed out after {} seconds waiting for the browser login. \
+            If no browser is available on this machine, use --login-method device.", timeout.as_secs());
+    }
+
+    fn handle(&self, mut stream: TcpStream, expected_state: &str) -> anyhow::Result<Request> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let head = read_request_head(&mut stream)?;
+        let request_line = head.lines().next().unwrap_or_default();
+        debug!("Redirect listener request: {}", request_line);
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let target = parts.next().unwrap_or_default();
+
+        let url = Url::parse(&format!("http://localhost:{}{}", self.port, target));
+        let url = match url {
+            Ok(url) if method == "GET" && url.path() == "/" => url,
+            _ => {
+                // Favicon requests, preflights and other noise
+                respond(&mut stream, "404 Not Found", "Not found", "")?;
+                return Ok(Request::Ignored);
+            }
+        };
+
+        let param = |name: &str| url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned());
+
+        // Any local process can send requests here, only the provider knows the state
+        if param("state").as_deref() != Some(expected_state) {
+            respond(&mut stream, "400 Bad Request", "Invalid request",
+                "The login state does not match, this page does not belong to the current login.")?;
+            return Ok(Request::Ignored);
+        }
+
+        if let Some(error) = param("error") {
+            let description = param("error_description").unwrap_or_default();
+            respond(&mut stream, "200 OK", "Authentication failed",
+                &format!("{}: {}", error, description))?;
+            let message = if description.is_empty() {
+                format!("Login failed: {}", error)
+            } else {
+                format!("Login failed: {} ({})", description, error)
+            };
+            return Ok(Request::Failed(anyhow!(message)));
+        }
+
+        match param("code") {
+            Some(code) => {
+                respond(&mut stream, "200 OK", "Authentication successful",
+                    "You can close this window and return to the terminal.")?;
+                Ok(Request::Callback(code))
+            }
+            None => {
+                respond(&mut stream, "400 Bad Request", "Invalid request", "No authorization code received.")?;
+                Ok(Request::Ignored)
+            }
+        }
+    }
+}
+
+fn parse_port_range(ports: &str) -> anyhow::Result<(u16, u16)> {
+    let invalid = || anyhow!("Invalid redirect port range '{}'. Use a port (8765), a range (8765-8775) or 0.", ports);
+    let (first, last) = match ports.split_once('-') {
+        Some((first, last)) => (first.trim(), last.trim()),
+        None => (ports.trim(), ports.trim()),
+    };
+    let first: u16 = first.parse().map_err(|_| invalid())?;
+    let last: u16 = last.parse().map_err(|_| invalid())?;
+    if first > last || (first == 0 && last != 0) {
+        return Err(invalid());
+    }
+    Ok((first, last))
+}
+
+fn read_request_head(stream: &mut TcpStream) -> anyhow::Result<String> {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") && head.len() < 16 * 1024 {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn respond(stream: &mut TcpStream, status: &str, title: &str, message: &str) -> anyhow::Result<()> {
+    let body = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>cscs-key: {title}</title></head>\
+        <body style=\"font-family: sans-serif; margin: 3em\"><h1>{title}</h1><p>{message}</p></body></html>\n",
+        title = html_escape(title),
+        message = html_escape(message),
+    );
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body,
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_port_range_accepts_ports_ranges_and_zero() {
+        assert_eq!(parse_port_range("8765").unwrap(), (8765, 8765));
+        assert_eq!(parse_port_range("8765-8775").unwrap(), (8765, 8775));
+        assert_eq!(parse_port_range(" 8765 - 8775 ").unwrap(), (8765, 8775));
+        assert_eq!(parse_port_range("0").unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn parse_port_range_rejects_invalid_ranges() {
+        for ports in ["", "http", "8775-8765", "0-10", "8765-", "-8765", "65536", "1-2-3"] {
+            assert!(parse_port_range(ports).is_err(), "{} should be rejected", ports);
+        }
+    }
+
+    fn send(port: u16, target: &str) -> String {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn ephemeral_port_is_used_in_redirect_uri() {
+        let listener = RedirectListener::bind("0").unwrap();
+        assert_ne!(listener.port, 0);
+        assert_eq!(listener.redirect_uri(), format!("http://localhost:{}", listener.port));
+    }
+
+    #[test]
+    fn requests_with_another_state_are_ignored() {
+        let listener = RedirectListener::bind("0").unwrap();
+        let port = listener.port;
+        let client = std::thread::spawn(move || {
+            let rejected = [
+                send(port, "/?error=access_denied&state=other"),
+                send(port, "/?code=forged&state=other"),
+                send(port, "/?code=forged"),
+                send(port, "/favicon.ico"),
+            ];
+            let accepted = send(port, "/?code=secret&state=expected");
+            (rejected, accepted)
+        });
+
+        let code = listener.wait_for_callback(Duration::from_secs(10), "expected").unwrap();
+        assert_eq!(code, "secret");
+        let (rejected, accepted) = client.join().unwrap();
+        assert!(rejected.iter().all(|response| !response.starts_with("HTTP/1.1 200")));
+        assert!(accepted.starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn provider_error_with_matching_state_fails_the_login() {
+        let listener = RedirectListener::bind("0").unwrap();
+        let port = listener.port;
+        let client = std::thread::spawn(move || send(port, "/?error=access_denied&error_description=Denied&state=expected"));
+
+        let error = listener.wait_for_callback(Duration::from_secs(10), "expected").unwrap_err();
+        assert_eq!(error.to_string(), "Login failed: Denied (access_denied)");
+        client.join().unwrap();
+    }
+}
@@ -1,28 +1,55 @@
 use clap::Parser;
 use directories::ProjectDirs;
+use std::collections::BTreeMap;
 use std::io::Write;
-use anyhow::Context;
+use std::path::Path;
+use std::process::ExitCode;
+use anyhow::{bail, Context};
 use figment::{Figment, providers::{Format, Toml, Serialized}};
 
-use crate::config::{Config, ConfigCliOverride};
+use crate::config::{Config, ConfigCliOverride, DEFAULT_PROFILE};
 
+#[cfg(unix)]
+mod agent;
+mod api;
 mod config;
+mod daemon;
+mod discovery;
+mod files;
+mod http;
 mod state;
 mod oidc;
+mod loopback;
+mod renewal;
+mod session;
 mod ssh;
+mod ssh_config;
+mod token_storage;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[arg(short, long, global = true, help = "Enable verbose output")]
     verbose: bool,
+    #[arg(long, global = true, help = "Configuration profile to use [env: CSCS_KEY_PROFILE]")]
+    profile: Option<String>,
     #[command(subcommand)]
     command: ssh::Commands,
     #[command(flatten)]
     pub config_overrides: ConfigCliOverride,
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(api::exit_code(&e))
+        }
+    }
+}
+
+fn run() -> anyhow::Result<()> {
     env_logger::builder()
         .format(|buf, record| {
             writeln!(buf, "{}", record.args())
@@ -37,17 +64,61 @@ fn main() -> anyhow::Result<()> {
     let config_file_path = config_dir.join("config.toml");
 
     //let config = config::Config::load()?;
-    let config: Config = Figment::new()
-        .merge(Serialized::defaults(Config::default()))
-        .merge(Toml::file(config_file_path))
-        .merge(Serialized::defaults(&cli.config_overrides))
-        .extract()?;
+    let config = load_config(&config_file_path, &cli)?;
 
     if cli.verbose {
         println!("Verbose output ...");
     }
 
+    // Commands acting on every profile need the configuration of each
+    if let ssh::Commands::Logout(args) = &cli.command
+        && args.all
+    {
+        let configs = load_all_configs(&config_file_path, &cli)?;
+        return session::logout(&configs, args);
+    }
+
     ssh::run(&cli.command, &config)?;
 
     Ok(())
 }
+
+// Top-level keys apply to all profiles, a `[profile.<name>]` section overrides them
+fn load_config(path: &Path, cli: &Cli) -> anyhow::Result<Config> {
+    let file = Figment::from(Toml::file(path));
+    let profile = match &cli.profile {
+        Some(profile) => profile.clone(),
+        None => match std::env::var("CSCS_KEY_PROFILE") {
+            Ok(profile) if !profile.is_empty() => profile,
+            _ => file.extract_inner("default_profile").unwrap_or_else(|_| DEFAULT_PROFILE.to_string()),
+        },
+    };
+    load_profile(path, cli, profile)
+}
+
+// The default profile and every `[profile.<name>]` section
+fn load_all_configs(path: &Path, cli: &Cli) -> anyhow::Result<Vec<Config>> {
+    let file = Figment::from(Toml::file(path));
+    let sections: BTreeMap<String, figment::value::Value> = file.extract_inner("profile").unwrap_or_default();
+    let mut profiles = vec![DEFAULT_PROFILE.to_string()];
+    profiles.extend(sections.into_keys().filter(|profile| profile != DEFAULT_PROFILE));
+    profiles.into_iter().map(|profile| load_profile(path, cli, profile)).collect()
+}
+
+fn load_profile(path: &Path, cli: &Cli, profile: String) -> anyhow::Result<Config> {
+    let file = Figment::from(Toml::file(path));
+    let section = format!("profile.{}", profile);
+    if profile != DEFAULT_PROFILE && !file.contains(&section) {
+        bail!("Profile '{}' is not defined in {}", profile, path.display());
+    }
+
+    let mut config: Config = Figment::new()
+        .merge(Serialized::defaults(Config::defaults_for(&profile)))
+        .merge(Toml::file(path))
+        .merge(file.focus(&section))
+        .merge(Serialized::defaults(&cli.config_overrides))
+        .extract()?;
+    config.profile = profile;
+
+    Ok(config)
+}
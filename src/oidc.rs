@@ -1,6 +1,4 @@
 //use std::fs::{File, metadata};
-use std::io::Write;
-use reqwest;
 use serde::Deserialize;
 //use anyhow::{anyhow, bail, Context};
 use anyhow::Context;
@@ -8,21 +6,26 @@ use chrono::{Utc, Duration};
 //use log::{info, debug};
 use log::info;
 
+use crate::api::{parse_json, ApiError};
 use crate::config::Config;
-use crate::state::{AppState, TokenStore};
-
-use openidconnect::core::{CoreClient, CoreProviderMetadata, CoreResponseType};
+use crate::discovery::discover;
+use crate::http::HttpClient;
+use crate::loopback::RedirectListener;
+use crate::state::{ServiceToken, TokenStore};
+use crate::token_storage::TokenStorage;
+
+use openidconnect::core::{
+    CoreClient, CoreDeviceAuthorizationResponse, CoreIdTokenVerifier, CoreResponseType, CoreTokenResponse,
+};
 use openidconnect::{
-    AuthenticationFlow, AuthorizationCode, ClientId, IssuerUrl,
-    PkceCodeChallenge, RedirectUrl, Scope,
+    AccessTokenHash, AuthenticationFlow, AuthorizationCode, ClaimsVerificationError, ClientId,
+    ErrorResponseType, PkceCodeChallenge, RedirectUrl, RequestTokenError, Scope, StandardErrorResponse,
     CsrfToken, Nonce,
     OAuth2TokenResponse,
     TokenResponse,
     RefreshToken,
 };
-use std::io::{BufRead, BufReader};
-use std::net::TcpListener;
-use url::Url;
+use ssh_key::HashAlg;
 
 #[derive(Deserialize, Debug)]
 struct ApiKeyResponse {
@@ -31,100 +34,304 @@ struct ApiKeyResponse {
     id_token: String,
 }
 
-pub fn get_access_token(config: &Config) -> anyhow::Result<String> {
-    if let Ok(api_key) = std::env::var("CSCS_API_KEY") {
+pub fn get_access_token(config: &Config, http_client: &HttpClient) -> anyhow::Result<String> {
+    Ok(get_token(config, http_client, TokenStore::grace_period())?.access_token)
+}
+
+/// Access token valid for at least `min_validity`, taken from the cache, refreshed or obtained by logging in
+pub fn get_token(config: &Config, http_client: &HttpClient, min_validity: Duration) -> anyhow::Result<TokenStore> {
+    if let Some(api_key) = read_api_key(config)? {
         info!("Authenticating via Service Account API Key...");
-        let new_store = login_via_api_key(config, &api_key)?;
-        // We probably DON'T want to save service account tokens to the user's home cache
-        return Ok(new_store.access_token);
+        return get_service_access_token(config, http_client, &api_key, min_validity);
     }
 
-    let mut state = AppState::load()?;
+    let storage = TokenStorage::from_config(config)?;
 
     // Try to load token from cache
-    if let Some(token) = state.oidc_token {
+    if let Some(token) = storage.load(&config.profile)?
+        && token.is_valid_for(min_validity)
+    {
+        info!("Token exists in store and is valid.");
+        return Ok(token);
+    }
+
+    // Only one process refreshes or logs in, the others wait and use its token
+    let _lock = storage.lock(&config.profile)?;
+    if let Some(token) = storage.load(&config.profile)? {
         info!("Token exists in store.");
-        // Is the access token still valid?
-        if !token.is_expired() {
-            info!("Token exists in store and is valid.");
-            return Ok(token.access_token);
+        if token.is_valid_for(min_validity) {
+            info!("Token was renewed by another process.");
+            return Ok(token);
         }
 
         // Token is expired, try to use the refresh token
         if let Some(refresh_token) = &token.refresh_token {
             info!("Access token expired, attempting refresh...");
-            match refresh_access_token(config, refresh_token) {
+            match refresh_access_token(config, http_client, refresh_token) {
                 Ok(new_token) => {
-                    let ret_access_token = new_token.access_token.clone();
-                    state.oidc_token = Some(new_token);
-                    state.save()?;
-                    return Ok(ret_access_token);
+                    storage.save(&config.profile, new_token.clone())?;
+                    return check_validity(new_token, min_validity);
                 }
                 Err(e) => {
-                    info!("Refresh failed: {}. Falling back to browser login.", e);
+                    info!("Refresh failed, falling back to interactive login: {:#}", e);
                 }
             }
         }
     }
 
-    info!("Token does not exist in store or was not refreshed -> browser authentication.");
-    // Cache or refresh failed -> Browser login
-    let new_token = login_via_browser(config)?;
-    let ret_access_token = new_token.access_token.clone();
-    state.oidc_token = Some(new_token);
-    state.save()?;
-    Ok(ret_access_token)
+    info!("Token does not exist in store or was not refreshed -> interactive authentication.");
+    // Cache or refresh failed -> Interactive login
+    let new_token = login_interactive(config, http_client)?;
+    storage.save(&config.profile, new_token.clone())?;
+    check_validity(new_token, min_validity)
 }
 
-fn refresh_access_token(config: &Config, refresh_token: &str) -> anyhow::Result<TokenStore> {
-    let http_client = reqwest::blocking::Client::new();
-    let issuer_url = IssuerUrl::new(config.issuer_url.clone())?;
-    let provider_metadata = CoreProviderMetadata::discover(&issuer_url, &http_client)?;
+// A fresh token that is still too short-lived means the provider does not issue longer ones
+fn check_validity(token: TokenStore, min_validity: Duration) -> anyhow::Result<TokenStore> {
+    if !token.is_valid_for(min_validity) {
+        let lifetime = token.expiration.map_or(Duration::zero(), |expiration| expiration - Utc::now());
+        anyhow::bail!("The identity provider caps the lifetime of access tokens: the new token is valid for {} seconds, \
+            less than the requested {} seconds. Logging in again will not help.",
+            lifetime.num_seconds(), min_validity.num_seconds());
+    }
+    Ok(token)
+}
 
-    let client = CoreClient::from_provider_metadata(
-        provider_metadata,
-        ClientId::new(config.pkce_client_id.clone()),
-        None,
-    );
+/// Send a request built with the access token, and if the service rejects the token,
+/// authenticate again (refresh or interactive login) and retry once
+pub fn send_authenticated(
+    config: &Config,
+    http_client: &HttpClient,
+    idempotent: bool,
+    request: impl Fn(&str) -> reqwest::blocking::RequestBuilder,
+) -> anyhow::Result<reqwest::blocking::Response> {
+    let access_token = get_access_token(config, http_client)?;
+    let response = http_client.send(request(&access_token), idempotent)?;
+    if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+        return Ok(response);
+    }
 
-    let token_response = client
-        .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))?
-        .request(&http_client)
-        .context("Failed to exchange refresh token")?;
+    info!("Access token was rejected, authenticating again");
+    invalidate_access_token(config, &access_token)?;
+    let access_token = get_access_token(config, http_client)?;
+    Ok(http_client.send(request(&access_token), idempotent)?)
+}
+
+// Forget the rejected access token, keeping the refresh token for the next attempt.
+// A token that another process renewed in the meantime is kept.
+fn invalidate_access_token(config: &Config, rejected: &str) -> anyhow::Result<()> {
+    let storage = TokenStorage::from_config(config)?;
+    if let Some(api_key) = read_api_key(config)? {
+        let fingerprint = api_key_fingerprint(&api_key);
+        if storage.load_service(&fingerprint)?.is_some_and(|cached| cached.token.access_token == rejected) {
+            storage.remove_service(&fingerprint)?;
+        }
+        return Ok(());
+    }
 
+    let _lock = storage.lock(&config.profile)?;
+    if let Some(mut token) = storage.load(&config.profile)?
+        && token.access_token == rejected
+    {
+        token.expiration = None;
+        storage.save(&config.profile, token)?;
+    }
+    Ok(())
+}
+
+// Validate the ID token (signature, issuer, audience, expiry and nonce) and build the token store.
+// Without a nonce, any nonce echoed by the provider is accepted (refresh and device flows).
+fn token_store(
+    token_response: &CoreTokenResponse,
+    id_token_verifier: &CoreIdTokenVerifier,
+    nonce: Option<&Nonce>,
+) -> anyhow::Result<TokenStore> {
     let id_token = token_response
         .id_token()
         .ok_or_else(|| anyhow::anyhow!("Server did not return an ID token"))?;
+
+    let claims = match nonce {
+        Some(nonce) => id_token.claims(id_token_verifier, nonce),
+        None => id_token.claims(id_token_verifier, |_: Option<&Nonce>| Ok(())),
+    }
+    .map_err(|e| match e {
+        // Kept as a typed error, the signing keys may have been rotated
+        ClaimsVerificationError::SignatureVerification(_) => anyhow::Error::new(e).context("ID token verification failed"),
+        e => anyhow::anyhow!("ID token verification failed: {}", e),
+    })?;
+
+    // Bind the access token to the ID token if the provider included its hash
+    if let Some(expected_hash) = claims.access_token_hash() {
+        let actual_hash = AccessTokenHash::from_token(
+            token_response.access_token(),
+            id_token.signing_alg()?,
+            id_token.signing_key(id_token_verifier)?,
+        )?;
+        if actual_hash != *expected_hash {
+            anyhow::bail!("ID token verification failed: access token hash mismatch");
+        }
+    }
     let expires_in = token_response.expires_in().unwrap_or(std::time::Duration::ZERO);
-    let expiration = Utc::now() + Duration::from_std(expires_in).unwrap();
+    let expiration = Utc::now() + Duration::from_std(expires_in)?;
 
     Ok(TokenStore {
         access_token: token_response.access_token().secret().to_string(),
-        refresh_token: Some(token_response.refresh_token().unwrap().secret().to_string()),
+        refresh_token: token_response.refresh_token().map(|token| token.secret().to_string()),
         id_token: Some(id_token.to_string()),
         expiration: Some(expiration),
     })
 }
 
-fn login_via_browser(config: &Config) -> anyhow::Result<TokenStore> {
-    info!("Get OIDC token");
+// Validate the ID token, refreshing the cached provider keys once if the signature does not match
+fn verified_token_store(
+    config: &Config,
+    http_client: &HttpClient,
+    token_response: &CoreTokenResponse,
+    id_token_verifier: &CoreIdTokenVerifier,
+    nonce: Option<&Nonce>,
+) -> anyhow::Result<TokenStore> {
+    match token_store(token_response, id_token_verifier, nonce) {
+        Err(e) if e.downcast_ref::<ClaimsVerificationError>().is_some() => {
+            info!("{:#}, refreshing the provider signing keys", e);
+            let provider_metadata = discover(config, http_client, true)?;
+            let id_token_verifier = CoreIdTokenVerifier::new_public_client(
+                ClientId::new(config.pkce_client_id.clone()),
+                provider_metadata.issuer().clone(),
+                provider_metadata.jwks().clone(),
+            );
+            token_store(token_response, &id_token_verifier, nonce)
+        }
+        result => result,
+    }
+}
+
+// OAuth error responses of the provider become typed errors, transport failures keep their cause
+fn token_error<RE, T>(error: RequestTokenError<RE, StandardErrorResponse<T>>) -> anyhow::Error
+where
+    RE: std::error::Error + Send + Sync + 'static,
+    T: ErrorResponseType + AsRef<str> + std::fmt::Display + Send + Sync + 'static,
+{
+    match error {
+        RequestTokenError::ServerResponse(response) => {
+            ApiError::from_oauth(response.error().as_ref(), response.error_description().map(String::as_str)).into()
+        }
+        RequestTokenError::Parse(e, _) => ApiError::MalformedResponse { message: e.to_string() }.into(),
+        error => anyhow::Error::new(error),
+    }
+}
+
+fn refresh_access_token(config: &Config, http_client: &HttpClient, refresh_token: &str) -> anyhow::Result<TokenStore> {
+    let provider_metadata = discover(config, http_client, false)?;
+
+    let client = CoreClient::from_provider_metadata(
+        provider_metadata,
+        ClientId::new(config.pkce_client_id.clone()),
+        None,
+    );
 
-    // In 4.x, we create a reusable reqwest client first
-    let http_client = reqwest::blocking::Client::builder()
-        .redirect(reqwest::redirect::Policy::none()) // Recommended for OIDC security
-        .build()?;
+    let token_response = client
+        .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))?
+        .request(http_client)
+        .map_err(token_error)
+        .context("Failed to exchange refresh token")?;
 
-    let issuer_url = IssuerUrl::new(config.issuer_url.clone())?;
+    let mut token = verified_token_store(config, http_client, &token_response, &client.id_token_verifier(), None)?;
+    // The provider may not rotate the refresh token
+    if token.refresh_token.is_none() {
+        token.refresh_token = Some(refresh_token.to_string());
+    }
+
+    Ok(token)
+}
+
+fn login_interactive(config: &Config, http_client: &HttpClient) -> anyhow::Result<TokenStore> {
+    match config.login_method.as_str() {
+        "browser" => login_via_browser(config, http_client),
+        "device" => login_via_device_code(config, http_client),
+        "auto" => {
+            if browser_available() {
+                login_via_browser(config, http_client)
+            } else {
+                info!("No browser available, using the device authorization flow.");
+                login_via_device_code(config, http_client)
+            }
+        }
+        other => anyhow::bail!("Unknown login method '{}'. Use auto, browser or device.", other),
+    }
+}
+
+// Guess whether a browser can be opened on this machine
+fn browser_available() -> bool {
+    if std::env::var_os("SSH_CONNECTION").is_some() || std::env::var_os("SSH_TTY").is_some() {
+        return false;
+    }
+    if cfg!(all(unix, not(target_os = "macos")))
+        && std::env::var_os("DISPLAY").is_none()
+        && std::env::var_os("WAYLAND_DISPLAY").is_none()
+    {
+        return false;
+    }
+    true
+}
+
+// OAuth 2.0 Device Authorization Grant (RFC 8628)
+fn login_via_device_code(config: &Config, http_client: &HttpClient) -> anyhow::Result<TokenStore> {
+    info!("Get OIDC token using the device authorization flow");
+
+    let provider_metadata = discover(config, http_client, false)?;
+    let device_authorization_url = provider_metadata
+        .additional_metadata()
+        .device_authorization_endpoint
+        .clone()
+        .context("The OpenID provider does not support the device authorization flow")?;
+
+    let client = CoreClient::from_provider_metadata(
+        provider_metadata,
+        ClientId::new(config.pkce_client_id.clone()),
+        None,
+    )
+    .set_device_authorization_url(device_authorization_url);
+
+    let details: CoreDeviceAuthorizationResponse = client
+        .exchange_device_code()
+        .add_scope(Scope::new("openid".to_string()))
+        .request(http_client)
+        .map_err(token_error)
+        .context("Failed to request a device code")?;
+
+    match details.verification_uri_complete() {
+        Some(uri) => eprintln!("To log in, open the following URL in a browser:\n\n    {}\n\nand confirm the code: {}\n",
+            uri.secret(), details.user_code().secret()),
+        None => eprintln!("To log in, open the following URL in a browser:\n\n    {}\n\nand enter the code: {}\n",
+            details.verification_uri().as_str(), details.user_code().secret()),
+    }
+    eprintln!("Waiting for authorization...");
+
+    // Polls the token endpoint, honouring `interval` and `slow_down` until the code expires
+    let token_response = client
+        .exchange_device_access_token(&details)?
+        .request(http_client, std::thread::sleep, None)
+        .map_err(token_error)
+        .context("Device authorization failed")?;
+
+    verified_token_store(config, http_client, &token_response, &client.id_token_verifier(), None)
+}
+
+fn login_via_browser(config: &Config, http_client: &HttpClient) -> anyhow::Result<TokenStore> {
+    info!("Get OIDC token");
 
     // Discovery takes a reference to the client
-    let provider_metadata = CoreProviderMetadata::discover(&issuer_url, &http_client)?;
+    let provider_metadata = discover(config, http_client, false)?;
+
+    // Bind before opening the browser so the redirect cannot race the listener
+    let listener = RedirectListener::bind(&config.redirect_ports)?;
 
     let client = CoreClient::from_provider_metadata(
         provider_metadata,
         ClientId::new(config.pkce_client_id.clone()),
         None,
     )
-    .set_redirect_uri(RedirectUrl::new("http://localhost:8765".to_string())?);
+    .set_redirect_uri(RedirectUrl::new(listener.redirect_uri())?);
 
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
@@ -141,72 +348,123 @@ fn login_via_browser(config: &Config) -> anyhow::Result<TokenStore> {
     // Open the browser!
     if let Err(e) = webbrowser::open(auth_url.as_str()) {
         eprintln!("Failed to open browser automatically: {}", e);
-        println!("Browser window did not open automatically. Log in here :\n{}", auth_url);
+        eprintln!("Browser window did not open automatically. Log in here :\n{}", auth_url);
     }
 
-    // Simple listener
-    let listener = TcpListener::bind("127.0.0.1:8765")?;
-    let (mut stream, _) = listener.accept()?;
-    let mut reader = BufReader::new(&stream);
-    let mut request_line = String::new();
-    reader.read_line(&mut request_line)?;
+    let login_timeout = duration_str::parse(&config.login_timeout)
+        .map_err(|e| anyhow::anyhow!("Invalid login timeout '{}': {}", config.login_timeout, e))?;
+    // Redirects without the CSRF state of this login are ignored by the listener
+    let code = listener.wait_for_callback(login_timeout, csrf_token.secret())?;
+
+    // Pass the reference to the client here too
+    let token_response = client
+        .exchange_code(AuthorizationCode::new(code))?
+        .set_pkce_verifier(pkce_verifier)
+        .request(http_client) // Look Ma, no http_client() helper!
+        .map_err(token_error)
+        .context("Failed to exchange the authorization code")?;
 
-    let redirect_url = request_line.split_whitespace().nth(1).unwrap_or("");
-    let url = Url::parse(&format!("http://localhost:8765{}", redirect_url))?;
+    // Check signature, claims and nonce: Replay protection
+    verified_token_store(config, http_client, &token_response, &client.id_token_verifier(), Some(&nonce))
+}
 
-    // Check CSRF: Unlikely on localhost, but better be careful
-    let returned_state = url.query_pairs()
-        .find(|(k, _)| k == "state")
-        .map(|(_, v)| v.into_owned())
-        .context("No state found")?;
-    if returned_state != *csrf_token.secret() {
-        return Err(anyhow::anyhow!("CSRF detected! State mismatch."));
+// The API key from CSCS_API_KEY, `Config::api_key_file` or `Config::api_key_command`, in this order
+fn read_api_key(config: &Config) -> anyhow::Result<Option<String>> {
+    if let Ok(api_key) = std::env::var("CSCS_API_KEY")
+        && !api_key.is_empty()
+    {
+        return Ok(Some(api_key));
     }
 
-    let code = url.query_pairs()
-        .find(|(key, _)| key == "code")
-        .map(|(_, value)| value.into_owned())
-        .context("No code found")?;
+    let api_key = if let Some(path) = &config.api_key_file {
+        info!("Reading API key from {}", path.display());
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read API key file {}", path.display()))?
+    } else if let Some(command) = &config.api_key_command {
+        info!("Reading API key from command `{}`", command);
+        let output = shell_command(command)
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .with_context(|| format!("Failed to run API key command `{}`", command))?;
+        if !output.status.success() {
+            anyhow::bail!("API key command `{}` failed ({})", command, output.status);
+        }
+        String::from_utf8(output.stdout).context("API key command printed invalid UTF-8")?
+    } else {
+        return Ok(None);
+    };
+
+    let api_key = api_key.trim();
+    if api_key.is_empty() {
+        anyhow::bail!("The configured API key is empty");
+    }
+    Ok(Some(api_key.to_string()))
+}
 
-    let response = "HTTP/1.1 200 OK\r\n\r\nAuthentication successful.\nYou can return to the terminal.";
-    stream.write_all(response.as_bytes())?;
+#[cfg(unix)]
+fn shell_command(command: &str) -> std::process::Command {
+    let mut shell = std::process::Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
 
-    // Pass the reference to the client here too
-    let token_response = client
-        .exchange_code(AuthorizationCode::new(code))?
-        .set_pkce_verifier(pkce_verifier)
-        .request(&http_client)?; // Look Ma, no http_client() helper!
+#[cfg(windows)]
+fn shell_command(command: &str) -> std::process::Command {
+    let mut shell = std::process::Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
 
-    // Check nonce: Replay protection
-    let id_token = token_response
-        .id_token()
-        .ok_or_else(|| anyhow::anyhow!("Server did not return an ID token"))?;
-    let id_token_verifier = client.id_token_verifier();
-    let expires_in = token_response.expires_in().unwrap_or(std::time::Duration::ZERO);
-    let expiration = Utc::now() + Duration::from_std(expires_in).unwrap();
+// Service-account tokens are stored by API key fingerprint, apart from the user's own tokens
+fn get_service_access_token(
+    config: &Config,
+    http_client: &HttpClient,
+    api_key: &str,
+    min_validity: Duration,
+) -> anyhow::Result<TokenStore> {
+    let fingerprint = api_key_fingerprint(api_key);
+
+    let storage = TokenStorage::from_config(config)?;
+    if let Some(cached) = storage.load_service(&fingerprint)?
+        && cached.token_url == config.service_token_url
+        && cached.token.is_valid_for(min_validity)
+    {
+        info!("Service token exists in store and is valid.");
+        return Ok(cached.token);
+    }
 
-    Ok(TokenStore {
-        access_token: token_response.access_token().secret().to_string(),
-        refresh_token: Some(token_response.refresh_token().unwrap().secret().to_string()),
-        id_token: Some(id_token.to_string()),
-        expiration: Some(expiration),
-    })
+    let token = login_via_api_key(config, http_client, api_key)?;
+    storage.save_service(&fingerprint, ServiceToken {
+        token_url: config.service_token_url.clone(),
+        token: token.clone(),
+    })?;
+    check_validity(token, min_validity)
 }
 
-fn login_via_api_key(config: &Config, api_key: &str) -> anyhow::Result<TokenStore> {
-    info!("Get OIDC token using API Key");
+/// Fingerprint of the configured API key, under which its service-account token is stored
+pub fn service_token_fingerprint(config: &Config) -> anyhow::Result<Option<String>> {
+    Ok(read_api_key(config)?.map(|api_key| api_key_fingerprint(&api_key)))
+}
 
-    let token_url = "https://api-service-account.hpc-user.tds.cscs.ch/api/v1/auth/token".to_string();
+fn api_key_fingerprint(api_key: &str) -> String {
+    HashAlg::Sha256.digest(api_key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
 
-    let client = reqwest::blocking::Client::new();
+fn login_via_api_key(config: &Config, http_client: &HttpClient, api_key: &str) -> anyhow::Result<TokenStore> {
+    info!("Get OIDC token using API Key");
 
-    let response = client.post(token_url)
+    // Issuing another token is harmless, so the request can be retried
+    let request = http_client.post(&config.service_token_url)
         .header("X-API-Key", api_key)
         .header("Content-Type", "application/json")
-        .header("Accept", "application/json")
-        .send()?;
+        .header("Accept", "application/json");
+    let response = http_client.send(request, true)?;
 
-    let response_struct: ApiKeyResponse = response.json()?;
+    let response_struct: ApiKeyResponse = parse_json(response)
+        .context("Failed to get a service-account token")?;
 
     let expires_in = Duration::seconds(response_struct.expires_in);
     let expiration = Utc::now() + expires_in;
@@ -0,0 +1,89 @@
+use std::time::{Duration, SystemTime};
+use anyhow::anyhow;
+use log::{info, warn};
+use ssh_key::Certificate;
+
+use crate::config::Config;
+use crate::ssh::format_duration;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Time before expiry at which `agent` and `daemon` renew the certificate
+pub fn renew_margin(config: &Config) -> anyhow::Result<Duration> {
+    duration_str::parse(&config.renew_margin)
+        .map_err(|e| anyhow!("Invalid renewal margin '{}': {}", config.renew_margin, e))
+}
+
+/// Call `renew` whenever the certificate enters the renewal margin, backing off while it fails.
+/// Without a current certificate, `renew` is called right away.
+pub fn keep_renewed(margin: Duration, mut cert: Option<Certificate>, mut renew: impl FnMut() -> anyhow::Result<Certificate>) -> ! {
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        if let Some(cert) = &cert {
+            let renew_at = renewal_time(cert, margin);
+            info!("Next renewal in {}", format_duration(&renew_at.duration_since(SystemTime::now()).unwrap_or_default()));
+            sleep_until(renew_at);
+        }
+
+        match renew() {
+            Ok(renewed) => {
+                cert = Some(renewed);
+                backoff = MIN_BACKOFF;
+            }
+            Err(e) => {
+                warn!("Failed to renew the certificate, retrying in {}: {:#}", format_duration(&backoff), e);
+                sleep_until(SystemTime::now() + backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+// When the certificate enters the renewal margin, but never earlier than half its lifetime,
+// so that it is not renewed more often than twice per lifetime
+fn renewal_time(cert: &Certificate, margin: Duration) -> SystemTime {
+    let lifetime = Duration::from_secs(cert.valid_before().saturating_sub(cert.valid_after()));
+    // One second past the margin so that `ensure` considers the certificate due
+    cert.valid_before_time() - margin.min(lifetime / 2) + Duration::from_secs(1)
+}
+
+// Sleep in short steps so that a suspended machine renews promptly after waking up
+fn sleep_until(time: SystemTime) {
+    while let Ok(remaining) = time.duration_since(SystemTime::now()) {
+        std::thread::sleep(remaining.min(Duration::from_secs(60)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ssh_key::rand_core::OsRng;
+    use ssh_key::{certificate, Algorithm, PrivateKey};
+
+    fn certificate(valid_after: u64, valid_before: u64) -> Certificate {
+        let ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let mut builder = certificate::Builder::new_with_random_nonce(&mut OsRng, key.public_key(), valid_after, valid_before)
+            .unwrap();
+        builder.valid_principal("user").unwrap();
+        builder.sign(&ca).unwrap()
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn renews_when_the_margin_is_reached() {
+        let cert = certificate(1_000_000, 1_086_400);
+        assert_eq!(renewal_time(&cert, Duration::from_secs(3600)), at(1_086_400 - 3600 + 1));
+    }
+
+    #[test]
+    fn renews_at_most_twice_per_lifetime() {
+        let cert = certificate(1_000_000, 1_003_600);
+        assert_eq!(renewal_time(&cert, Duration::from_secs(86400)), at(1_003_600 - 1800 + 1));
+    }
+}
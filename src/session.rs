@@ -0,0 +1,381 @@
+use clap::Args;
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Context};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{info, debug, warn};
+use openidconnect::core::{CoreGenderClaim, CoreIdTokenVerifier, CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm};
+use openidconnect::{
+    AdditionalClaims, ClaimsVerificationError, ClientId, EndSessionUrl, IdToken, IdTokenClaims, Nonce, RevocationUrl,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::api::{check_response, parse_json};
+use crate::config::Config;
+use crate::discovery::{discover, ExtendedProviderMetadata};
+use crate::http::HttpClient;
+use crate::oidc::{get_token, send_authenticated, service_token_fingerprint};
+use crate::ssh::format_duration;
+use crate::state::TokenStore;
+use crate::token_storage::TokenStorage;
+
+// ID token claims beyond the standard ones
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct GroupClaims {
+    #[serde(default)]
+    groups: Vec<String>,
+}
+impl AdditionalClaims for GroupClaims {}
+
+type GroupIdToken = IdToken<GroupClaims, CoreGenderClaim, CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm>;
+type GroupIdTokenClaims = IdTokenClaims<GroupClaims, CoreGenderClaim>;
+
+#[derive(Args, Debug)]
+pub struct TokenArgs {
+    #[arg(long, help = "Print the token, its type and expiry as JSON")]
+    json: bool,
+    #[arg(long, value_name = "VAR", num_args = 0..=1, default_missing_value = "CSCS_ACCESS_TOKEN", conflicts_with = "json",
+        help = "Print shell export lines for VAR and VAR_EXPIRES_AT [default VAR: CSCS_ACCESS_TOKEN]")]
+    export: Option<String>,
+    #[arg(long, value_name = "DURATION", help = "Renew the token unless it remains valid for at least this long")]
+    min_validity: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct WhoamiArgs {
+    #[arg(long, help = "Also query the userinfo endpoint of the identity provider")]
+    userinfo: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct LogoutArgs {
+    #[arg(long, help = "Log out of every profile in the configuration file")]
+    pub all: bool,
+    #[arg(long, help = "Also end the single sign-on session at the identity provider")]
+    end_session: bool,
+}
+
+/// Revoke the tokens of each profile at the identity provider and remove them from every token storage.
+/// The memory storage holds nothing between commands, so logging out of it is an error.
+pub fn logout(configs: &[Config], args: &LogoutArgs) -> anyhow::Result<()> {
+    if let [config] = configs {
+        return logout_profile(config, args);
+    }
+
+    let mut failed = 0;
+    for config in configs {
+        if let Err(e) = logout_profile(config, args) {
+            eprintln!("Failed to log out of profile '{}': {:?}", config.profile, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("Failed to log out of {} of {} profiles", failed, configs.len());
+    }
+    Ok(())
+}
+
+fn logout_profile(config: &Config, args: &LogoutArgs) -> anyhow::Result<()> {
+    debug!("ssh-key logout subcommand");
+    debug!("{:?}", config);
+
+    let storage = TokenStorage::from_config(config)?;
+    if let TokenStorage::Memory = storage {
+        bail!("Tokens in memory storage are gone when the command that obtained them exits, there is nothing to log out of. \
+            Select the storage the tokens were saved in with --token-storage.");
+    }
+    // Service-account tokens are only dropped, the API key keeps issuing new ones
+    match service_token_fingerprint(config) {
+        Ok(Some(fingerprint)) => storage.remove_service_everywhere(&fingerprint)?,
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read the API key, its cached service-account token is kept: {:#}", e),
+    }
+    let Some(token) = storage.load(&config.profile)? else {
+        // Copies left in other storages by an earlier configuration are removed all the same
+        storage.remove_everywhere(&config.profile)?;
+        println!("Not logged in to profile '{}'.", config.profile);
+        return Ok(());
+    };
+
+    // The tokens are removed locally even if the provider cannot be reached
+    let revoked = HttpClient::new(config)
+        .and_then(|http_client| end_provider_session(config, &http_client, &token, args.end_session));
+    storage.remove_everywhere(&config.profile)?;
+    println!("Removed the tokens of profile '{}'.", config.profile);
+
+    revoked.context("The refresh token was removed locally, but remains valid at the provider until it expires")
+}
+
+fn end_provider_session(config: &Config, http_client: &HttpClient, token: &TokenStore, end_session: bool) -> anyhow::Result<()> {
+    let provider_metadata = discover(config, http_client, false)?;
+    let endpoints = provider_metadata.additional_metadata();
+
+    let Some(revocation_url) = &endpoints.revocation_endpoint else {
+        bail!("The identity provider {} does not support token revocation", config.issuer_url);
+    };
+    // Revoking the refresh token usually invalidates the access tokens issued with it as well
+    if let Some(refresh_token) = &token.refresh_token {
+        revoke(config, http_client, revocation_url, refresh_token, "refresh_token")
+            .context("Failed to revoke the refresh token")?;
+        println!("Revoked the refresh token.");
+    }
+    match revoke(config, http_client, revocation_url, &token.access_token, "access_token") {
+        Ok(()) => println!("Revoked the access token."),
+        // Not every provider revokes access tokens, they expire soon anyway
+        Err(e) => warn!("Failed to revoke the access token: {:?}", e),
+    }
+
+    if end_session {
+        let Some(end_session_url) = &endpoints.end_session_endpoint else {
+            bail!("The identity provider {} does not support ending the session", config.issuer_url);
+        };
+        end_sso_session(config, http_client, end_session_url, token.id_token.as_deref())
+            .context("Failed to end the session at the identity provider")?;
+        println!("Ended the session at the identity provider.");
+    }
+
+    Ok(())
+}
+
+// RFC 7009 token revocation, the provider answers 200 even for tokens it no longer knows
+fn revoke(
+    config: &Config,
+    http_client: &HttpClient,
+    revocation_url: &RevocationUrl,
+    token: &str,
+    token_type_hint: &str,
+) -> anyhow::Result<()> {
+    info!("Revoking {} at {}", token_type_hint, revocation_url.as_str());
+    let request = http_client.provider_post(revocation_url.url().clone())
+        .form(&[
+            ("token", token),
+            ("token_type_hint", token_type_hint),
+            ("client_id", &config.pkce_client_id),
+        ]);
+    check_response(http_client.send(request, true)?)?;
+    Ok(())
+}
+
+// OpenID Connect RP-initiated logout, the ID token identifies the session to end
+fn end_sso_session(
+    config: &Config,
+    http_client: &HttpClient,
+    end_session_url: &EndSessionUrl,
+    id_token: Option<&str>,
+) -> anyhow::Result<()> {
+    let Some(id_token) = id_token else {
+        bail!("No ID token is cached to identify the session");
+    };
+    info!("Ending the session at {}", end_session_url.as_str());
+    let request = http_client.provider_get(end_session_url.url().clone())
+        .query(&[("id_token_hint", id_token), ("client_id", &config.pkce_client_id)]);
+    let response = http_client.send(request, true)?;
+    // Providers answer with a confirmation page or a redirect to their own page
+    if !response.status().is_redirection() {
+        check_response(response)?;
+    }
+    Ok(())
+}
+
+/// Print a valid access token for other tools, e.g. `curl -H "Authorization: Bearer $(cscs-key token)"`
+pub fn print_token(config: &Config, args: &TokenArgs) -> anyhow::Result<()> {
+    debug!("ssh-key token subcommand");
+    debug!("{:?}", config);
+
+    let min_validity = match &args.min_validity {
+        Some(min_validity) => {
+            let min_validity = duration_str::parse(min_validity)
+                .map_err(|e| anyhow!("Invalid minimum validity '{}': {}", min_validity, e))?;
+            chrono::Duration::from_std(min_validity)?.max(TokenStore::grace_period())
+        }
+        None => TokenStore::grace_period(),
+    };
+    let token = get_token(config, &HttpClient::new(config)?, min_validity)?;
+    let expires_at = token.expiration.map(format_time);
+
+    if args.json {
+        let expires_in = token.expiration.map(|expiration| (expiration - Utc::now()).num_seconds());
+        let json = serde_json::json!({
+            "access_token": token.access_token,
+            "token_type": "Bearer",
+            "expires_at": expires_at,
+            "expires_in": expires_in,
+        });
+        println!("{}", serde_json::to_string_pretty(&json)?);
+    } else if let Some(var) = &args.export {
+        let valid_name = var.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && var.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            bail!("Invalid environment variable name '{}'", var);
+        }
+        // The token comes from the provider and is quoted like any other untrusted value
+        println!("export {}={}", var, shell_quote(&token.access_token));
+        println!("export {}_EXPIRES_AT={}", var, shell_quote(&expires_at.unwrap_or_default()));
+    } else {
+        println!("{}", token.access_token);
+    }
+
+    Ok(())
+}
+
+// Single-quote a value for POSIX shells, where nothing but the quote itself is special inside
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Show the identity of the cached tokens, verified against the provider's signing keys
+pub fn whoami(config: &Config, args: &WhoamiArgs) -> anyhow::Result<()> {
+    debug!("ssh-key whoami subcommand");
+    debug!("{:?}", config);
+
+    let Some(token) = TokenStorage::from_config(config)?.load(&config.profile)? else {
+        println!("Not logged in to profile '{}'.", config.profile);
+        return Ok(());
+    };
+    let id_token = token.id_token.as_deref()
+        .context("No ID token is cached, log in again")?;
+    let id_token = GroupIdToken::from_str(id_token).context("Invalid cached ID token")?;
+
+    let http_client = HttpClient::new(config)?;
+    let provider_metadata = discover(config, &http_client, false)?;
+    let (claims, expired) = match verify_id_token(config, &provider_metadata, &id_token) {
+        Err(ClaimsVerificationError::SignatureVerification(_)) => {
+            info!("ID token signature does not match, refreshing the provider signing keys");
+            verify_id_token(config, &discover(config, &http_client, true)?, &id_token)
+        }
+        result => result,
+    }
+    .context("The cached ID token could not be verified")?;
+
+    let name = claims.name().and_then(|name| name.get(None)).map(|name| name.as_str());
+    let email = claims.email().map(|email| match claims.email_verified() {
+        Some(true) => format!("{} (verified)", email.as_str()),
+        _ => email.as_str().to_string(),
+    });
+    let groups = &claims.additional_claims().groups;
+
+    println!("Profile:          {}", config.profile);
+    println!("Issuer:           {}", claims.issuer().as_str());
+    println!("Username:         {}", claims.preferred_username().map_or("-", |username| username.as_str()));
+    println!("Name:             {}", name.unwrap_or("-"));
+    println!("Email:            {}", email.as_deref().unwrap_or("-"));
+    println!("Subject:          {}", claims.subject().as_str());
+    println!("Groups:           {}", if groups.is_empty() { "-".to_string() } else { groups.join(", ") });
+    println!("Authenticated:    {}", claims.auth_time().map_or("-".to_string(), format_time));
+    println!("ID token:         {}", describe_expiry(Some(claims.expiration())));
+    println!("Access token:     {}", describe_expiry(token.expiration));
+    println!("Refresh token:    {}", describe_refresh_token(token.refresh_token.as_deref()));
+    if expired {
+        println!();
+        println!("The ID token has expired, the claims above are from the last login or refresh.");
+    }
+
+    if args.userinfo {
+        print_userinfo(config, &http_client, &provider_metadata)?;
+    }
+
+    Ok(())
+}
+
+// Check signature, issuer, audience and expiry against the current time, returning the claims and
+// whether the token has expired. An expired ID token still identifies the user the cached tokens
+// belong to, so expiry is the only failure that is tolerated.
+fn verify_id_token(
+    config: &Config,
+    provider_metadata: &ExtendedProviderMetadata,
+    id_token: &GroupIdToken,
+) -> Result<(GroupIdTokenClaims, bool), ClaimsVerificationError> {
+    let verifier = || CoreIdTokenVerifier::new_public_client(
+        ClientId::new(config.pkce_client_id.clone()),
+        provider_metadata.issuer().clone(),
+        provider_metadata.jwks().clone(),
+    );
+    match id_token.claims(&verifier(), no_nonce) {
+        Ok(claims) => Ok((claims.clone(), false)),
+        // Only reported once the signature, issuer and audience have been verified
+        Err(ClaimsVerificationError::Expired(reason)) => {
+            info!("{}", reason);
+            // Even the insecure verifier checks the expiry, so it is read at the earliest possible time
+            let unverified = CoreIdTokenVerifier::new_insecure_without_verification().set_time_fn(|| DateTime::<Utc>::MIN_UTC);
+            let expiration = id_token.claims(&unverified, no_nonce)?.expiration();
+            let before_expiry = verifier().set_time_fn(move || expiration - chrono::Duration::seconds(1));
+            Ok((id_token.claims(&before_expiry, no_nonce)?.clone(), true))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn no_nonce(_: Option<&Nonce>) -> Result<(), String> {
+    Ok(())
+}
+
+fn print_userinfo(config: &Config, http_client: &HttpClient, provider_metadata: &ExtendedProviderMetadata) -> anyhow::Result<()> {
+    let Some(userinfo_url) = provider_metadata.userinfo_endpoint() else {
+        bail!("The identity provider {} has no userinfo endpoint", config.issuer_url);
+    };
+    info!("Querying {}", userinfo_url.as_str());
+    let response = send_authenticated(config, http_client, true, |access_token| {
+        http_client.provider_get(userinfo_url.url().clone()).bearer_auth(access_token)
+    })?;
+    let userinfo: serde_json::Map<String, serde_json::Value> = parse_json(response)
+        .context("Failed to query the userinfo endpoint")?;
+
+    println!();
+    println!("Userinfo:");
+    for (claim, value) in &userinfo {
+        match value {
+            serde_json::Value::String(value) => println!("  {}: {}", claim, value),
+            value => println!("  {}: {}", claim, value),
+        }
+    }
+    Ok(())
+}
+
+// Refresh tokens are opaque to clients, some providers (Keycloak) issue JWTs carrying their expiry
+fn describe_refresh_token(refresh_token: Option<&str>) -> String {
+    let Some(refresh_token) = refresh_token else {
+        return "none".to_string();
+    };
+    let claims = refresh_token.split('.').nth(1)
+        .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+        .and_then(|payload| serde_json::from_slice::<serde_json::Value>(&payload).ok());
+    let Some(claims) = claims else {
+        return "lifetime not exposed by the provider".to_string();
+    };
+    match claims.get("exp").and_then(|exp| exp.as_i64()).filter(|exp| *exp > 0) {
+        Some(exp) => describe_expiry(DateTime::from_timestamp(exp, 0)),
+        None => "does not expire".to_string(),
+    }
+}
+
+fn describe_expiry(expiration: Option<DateTime<Utc>>) -> String {
+    let Some(expiration) = expiration else {
+        return "expired".to_string();
+    };
+    let now = Utc::now();
+    match (expiration - now).to_std() {
+        Ok(remaining) => format!("valid until {} (expires in {})", format_time(expiration), format_duration(&remaining)),
+        Err(_) => {
+            let expired_since = (now - expiration).to_std().unwrap_or_default();
+            format!("expired at {} ({} ago)", format_time(expiration), format_duration(&expired_since))
+        }
+    }
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_quote_escapes_single_quotes() {
+        assert_eq!(shell_quote("eyJhbGciOi.J9-_x"), "'eyJhbGciOi.J9-_x'");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a'b"), "'a'\\''b'");
+        assert_eq!(shell_quote("$(rm -rf ~)'; echo"), "'$(rm -rf ~)'\\''; echo'");
+    }
+}
@@ -1,27 +1,179 @@
-use clap::Subcommand;
+use clap::{Args, ArgGroup, Subcommand};
 use std::fs;
-use std::fs::{File, metadata};
-use std::io::Write;
+use std::io::{IsTerminal, Write};
 use std::fmt::Debug;
 use std::time::SystemTime;
 use std::path::PathBuf;
-use reqwest;
 use serde::{Serialize, Deserialize, Deserializer};
-use anyhow::{anyhow, bail};
-use log::{info, debug};
-
+use anyhow::{anyhow, bail, Context};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{info, debug, warn};
+use ssh_key::{Algorithm, Certificate, EcdsaCurve, HashAlg, LineEnding, PrivateKey};
+use ssh_key::private::RsaKeypair;
+use ssh_key::rand_core::OsRng;
+
+#[cfg(unix)]
+use crate::agent::{self, AgentArgs};
+use crate::api::{check_response, parse_json, ApiError};
 use crate::config::Config;
-use crate::oidc::get_access_token;
+use crate::daemon::{self, InstallServiceArgs};
+use crate::files;
+use crate::http::HttpClient;
+use crate::oidc::send_authenticated;
+use crate::session::{self, LogoutArgs, TokenArgs, WhoamiArgs};
+use crate::ssh_config::{self, SshConfigArgs};
+use crate::state::{AppState, CertMetadata};
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
+    /// Download a new key pair generated by the SSH service
     GenOIDC,
+    /// Sign a locally generated key pair (created on first use); the private key never leaves this machine
     SignOIDC,
     Status,
-    List,
-    Revoke,
+    List(ListArgs),
+    Revoke(RevokeArgs),
+    /// Renew the certificate only if it expires within the renewal margin (for ssh `Match exec`)
+    Ensure,
+    /// Write an OpenSSH config include for the CSCS clusters
+    SshConfig(SshConfigArgs),
+    /// Run an ssh-agent holding an in-memory key whose certificate is renewed automatically
+    #[cfg(unix)]
+    Agent(AgentArgs),
+    /// Keep the certificate at the key path valid, renewing it in the background
+    Daemon,
+    /// Install a systemd user service and timer running the renewal daemon
+    InstallService(InstallServiceArgs),
+    /// Print a valid access token (logging in if needed) for scripts calling CSCS APIs
+    Token(TokenArgs),
+    /// Show the identity the cached tokens belong to
+    Whoami(WhoamiArgs),
+    /// Revoke the cached tokens at the identity provider and remove them from every token storage
+    Logout(LogoutArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ListArgs {
+    #[arg(long, help = "Show the certificates recorded locally instead of querying the SSH service")]
+    local: bool,
+}
+
+#[derive(Args, Debug)]
+#[command(group(
+    ArgGroup::new("selector")
+        .required(true)
+        .multiple(true)
+        .args(["serial", "fingerprint", "all", "expired", "current"])
+))]
+pub struct RevokeArgs {
+    #[arg(long, value_name = "SERIAL", help = "Revoke the key with this serial number (repeatable)")]
+    serial: Vec<String>,
+    #[arg(long, value_name = "FINGERPRINT", help = "Revoke the key with this fingerprint (repeatable)")]
+    fingerprint: Vec<String>,
+    #[arg(long, help = "Revoke all keys issued to you")]
+    all: bool,
+    #[arg(long, help = "Revoke all expired keys")]
+    expired: bool,
+    #[arg(long, help = "Revoke the key currently stored at the configured key path")]
+    current: bool,
+    #[arg(short, long, help = "Do not ask for confirmation")]
+    yes: bool,
+    #[arg(long, help = "Delete the local key files of revoked keys")]
+    delete_files: bool,
+}
+
+/// Local files written by one of the key flows below `Config::key_path`
+pub struct KeyFiles {
+    pub flow: &'static str,
+    pub private_key: PathBuf,
+    pub public_key: Option<PathBuf>,
+    pub certificate: PathBuf,
+}
+
+impl KeyFiles {
+    /// Files written by `gen-oidc`: the server generated the key pair
+    pub fn generated(config: &Config) -> Self {
+        Self {
+            flow: "gen-oidc",
+            private_key: config.key_path.clone(),
+            public_key: None,
+            certificate: PathBuf::from(format!("{}-cert.pub", config.key_path.display())),
+        }
+    }
+
+    /// Files used by `sign-oidc`: a local key pair and the certificate signed for it
+    pub fn signed(config: &Config) -> Self {
+        Self {
+            flow: "sign-oidc",
+            private_key: PathBuf::from(format!("{}-signing", config.key_path.display())),
+            public_key: Some(PathBuf::from(format!("{}-signing.pub", config.key_path.display()))),
+            certificate: PathBuf::from(format!("{}-signing-cert.pub", config.key_path.display())),
+        }
+    }
+
+    /// Files of the flow selected by `Config::issue_method`
+    pub fn configured(config: &Config) -> anyhow::Result<Self> {
+        match config.issue_method.as_str() {
+            "sign" => Ok(Self::signed(config)),
+            "gen" => Ok(Self::generated(config)),
+            other => bail!("Unknown issue method '{}'. Use sign or gen.", other),
+        }
+    }
+
+    fn all(&self) -> Vec<&PathBuf> {
+        let mut files = vec![&self.private_key, &self.certificate];
+        files.extend(self.public_key.iter());
+        files
+    }
+
+    /// Parse the OpenSSH certificate, returns `None` if it does not exist
+    pub fn read_certificate(&self) -> anyhow::Result<Option<Certificate>> {
+        let content = match fs::read_to_string(&self.certificate) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => bail!("Error accessing SSH certificate at {}: {}", self.certificate.display(), e),
+        };
+        let cert = Certificate::from_openssh(content.trim())
+            .map_err(|e| anyhow!("Failed to parse SSH certificate {}: {}", self.certificate.display(), e))?;
+        Ok(Some(cert))
+    }
+
+    /// Whether the private key exists and the certificate stays valid for at least `margin`
+    pub fn is_valid_for(&self, margin: std::time::Duration) -> bool {
+        if !self.private_key.exists() {
+            return false;
+        }
+        match self.read_certificate() {
+            Ok(Some(cert)) => cert_status(&cert) == CertStatus::Valid
+                && cert.valid_before_time()
+                    .duration_since(SystemTime::now())
+                    .is_ok_and(|remaining| remaining >= margin),
+            Ok(None) => false,
+            Err(e) => {
+                warn!("{}", e);
+                false
+            }
+        }
+    }
+
+    /// Serial number and fingerprint of the certificate, if it exists and can be parsed
+    fn identity(&self) -> Option<(String, String)> {
+        match self.read_certificate() {
+            Ok(cert) => cert.map(|cert| (
+                cert.serial().to_string(),
+                cert.public_key().fingerprint(HashAlg::Sha256).to_string(),
+            )),
+            Err(e) => {
+                warn!("{}", e);
+                None
+            }
+        }
+    }
 }
 
+/// Comment prefix of the identities this tool adds to ssh-agent
+const AGENT_COMMENT_PREFIX: &str = "cscs-key";
+
 #[derive(Serialize)]
 struct SshKeyDuration {
     duration: String,
@@ -52,10 +204,10 @@ struct SshserviceResponseNew {
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct SshKeyCertNew {
+pub struct SshKeyCertNew {
     #[serde(deserialize_with = "ensure_newline")]
-    public_key: String,
-    expire_time: String,
+    pub public_key: String,
+    pub expire_time: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -64,6 +216,38 @@ struct SshserviceResponseCertNew {
     ssh_key: SshKeyCertNew,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SshKeyInfo {
+    #[serde(deserialize_with = "string_or_number")]
+    serial_number: String,
+    #[serde(default)]
+    fingerprint: String,
+    #[serde(default)]
+    create_time: String,
+    #[serde(default)]
+    expire_time: String,
+    #[serde(default)]
+    comment: String,
+}
+
+impl SshKeyInfo {
+    fn is_expired(&self) -> bool {
+        DateTime::parse_from_rfc3339(&self.expire_time)
+            .map(|expire_time| expire_time <= Utc::now())
+            .unwrap_or(true)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SshserviceResponseList {
+    #[serde(default)]
+    ssh_keys: Vec<SshKeyInfo>,
+    #[serde(default)]
+    next_page_token: Option<String>,
+}
+
 // Ensure downloaded ssh keys end with \n
 fn ensure_newline<'de, D>(deserializer: D) -> Result<String, D::Error>
 where
@@ -78,20 +262,49 @@ where
     Ok(s)
 }
 
+// Serial numbers are 64-bit and may come back as JSON numbers or strings
+fn string_or_number<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(s) => Ok(s),
+        serde_json::Value::Number(n) => Ok(n.to_string()),
+        other => Err(serde::de::Error::custom(format!("expected string or number, got {}", other))),
+    }
+}
+
 pub fn run(command: &Commands, config: &Config) -> anyhow::Result<()> {
     debug!{"ssh-key command"};
     match command {
-        Commands::GenOIDC => download_key_oidc(&config)?,
-        Commands::SignOIDC => sign_key_oidc(&config)?,
-        Commands::Status => status_key(&config)?,
-        Commands::List => list_keys(&config)?,
-        Commands::Revoke => revoke_keys(&config)?,
+        Commands::GenOIDC => {
+            let _lock = lock_keys(config)?;
+            download_key_oidc(config, &HttpClient::new(config)?)?
+        }
+        Commands::SignOIDC => {
+            let _lock = lock_keys(config)?;
+            sign_key_oidc(config, &HttpClient::new(config)?)?
+        }
+        Commands::Status => status_key(config)?,
+        // Local certificates need no network
+        Commands::List(args) if args.local => list_local_certs()?,
+        Commands::List(_) => list_keys(config, &HttpClient::new(config)?)?,
+        Commands::Revoke(args) => revoke_keys(config, &HttpClient::new(config)?, args)?,
+        Commands::Ensure => ensure_key(config, &HttpClient::new(config)?)?,
+        Commands::SshConfig(args) => ssh_config::write_ssh_config(config, args)?,
+        #[cfg(unix)]
+        Commands::Agent(args) => agent::run_agent(config, args)?,
+        Commands::Daemon => daemon::run_daemon(config)?,
+        Commands::InstallService(args) => daemon::install_service(config, args)?,
+        Commands::Token(args) => session::print_token(config, args)?,
+        Commands::Whoami(args) => session::whoami(config, args)?,
+        Commands::Logout(args) => session::logout(std::slice::from_ref(config), args)?,
     }
 
     Ok(())
 }
 
-fn download_key_oidc(config: &Config) -> anyhow::Result<()> {
+fn download_key_oidc(config: &Config, client: &HttpClient) -> anyhow::Result<()> {
     debug!("ssh-key gen-new subcommand");
     debug!("{:?}", config);
 
@@ -101,183 +314,616 @@ fn download_key_oidc(config: &Config) -> anyhow::Result<()> {
 
     info!("Get OIDC token");
 
-    let access_token = get_access_token(&config)?;
-    println!("got token: {}", access_token);
-
-    let client = reqwest::blocking::Client::new();
+    // Every request generates a new key pair, so it is only retried if it was not processed
+    let response = send_authenticated(config, client, false, |access_token| {
+        client.post(config.keys_url.clone())
+            .bearer_auth(access_token)
+            .json(&key_duration)
+    })?;
+
+    let response_struct: SshserviceResponseNew = parse_json(response)
+        .context("Failed to download SSH key")?;
+
+    let key_files = KeyFiles::generated(config);
+    let private_key_path = key_files.private_key.clone();
+    let public_key_path = key_files.certificate.clone();
+
+    // Replace key and certificate together, a failure leaves the previous pair in place
+    info!("Saving private key in {} and certificate in {}", private_key_path.display(), public_key_path.display());
+    files::write_pair(
+        (&private_key_path, response_struct.ssh_key.private_key.as_bytes(), files::PRIVATE),
+        (&public_key_path, response_struct.ssh_key.public_key.as_bytes(), files::PUBLIC),
+    )?;
+    info!("Public SSH key successfully downloaded to {}", public_key_path.display());
+    println!("Private SSH key successfully downloaded to: {}", private_key_path.display());
+    info!("SSH key expires at: {}", response_struct.ssh_key.expire_time);
 
-    let response = client.post(config.keys_url.clone())
-        .bearer_auth(&access_token)
-        .json(&key_duration)
-        .send()?;
+    record_certificate(config, &key_files, &response_struct.ssh_key.public_key, &response_struct.ssh_key.expire_time)?;
 
-    if !response.status().is_success() {
-        //let error_text = response.text().unwrap_or_else(|_| "Failed to read error response".to_string());
-        //bail!("Failed to download SSH key. HTTP status: {}. Response: {}", response.status(), error_text);
+    if config.add_to_agent {
+        add_to_agent(&key_files)?;
     }
 
-    let response_struct: SshserviceResponseNew = response.json()?;
+    Ok(())
+}
 
-    let private_key_path = config.key_path.clone();
-    let public_key_path = PathBuf::from(format!("{}-cert.pub", private_key_path.display()));
+fn sign_key_oidc(config: &Config, client: &HttpClient) -> anyhow::Result<()> {
+    debug!("ssh-key gen-new subcommand");
+    debug!("{:?}", config);
 
-    if let Some(parent) = private_key_path.parent() {
-        std::fs::create_dir_all(parent)?;
-    }
+    let key_files = KeyFiles::signed(config);
+    let public_key_path = key_files.public_key.clone().unwrap();
+    ensure_signing_key(&key_files, &config.key_type)?;
+    info!("Reading public key in {}", public_key_path.display());
+    let content = fs::read_to_string(public_key_path)?;
+
+    let signed_key = request_certificate(config, client, &content)?;
+
+    let public_key_path = key_files.certificate.clone();
 
     // Save public key
-    let mut public_file = File::create(&public_key_path)?;
     info!("Saving public key in {}", public_key_path.display());
-    public_file.write_all(response_struct.ssh_key.public_key.as_bytes())?;
-    #[cfg(unix)] // Only apply on Unix-like systems
-    {
-        info!("Setting permissions for public key to 0o644: {}", public_key_path.display());
-        use std::os::unix::fs::PermissionsExt;
-        let mut permissions = public_file.metadata()?.permissions();
-        permissions.set_mode(0o644); // Read/write for owner only
-        std::fs::set_permissions(&public_key_path, permissions)?;
-    }
+    files::write_atomic(&public_key_path, signed_key.public_key.as_bytes(), files::PUBLIC)?;
     info!("Public SSH key successfully downloaded to {}", public_key_path.display());
+    println!("Signed SSH certificate saved to: {}", public_key_path.display());
+    info!("SSH certificate expires at: {}", signed_key.expire_time);
 
-    // Save private key
-    let mut private_file = File::create(&private_key_path)?;
-    info!("Saving private key in {}", private_key_path.display());
-    private_file.write_all(response_struct.ssh_key.private_key.as_bytes())?;
-    #[cfg(unix)] // Only apply on Unix-like systems
-    {
-        info!("Setting permissions for private key to 0o600: {}", private_key_path.display());
-        use std::os::unix::fs::PermissionsExt;
-        let mut permissions = private_file.metadata()?.permissions();
-        permissions.set_mode(0o600); // Read/write for owner only
-        std::fs::set_permissions(&private_key_path, permissions)?;
+    record_certificate(config, &key_files, &signed_key.public_key, &signed_key.expire_time)?;
+
+    if config.add_to_agent {
+        add_to_agent(&key_files)?;
     }
-    println!("Private SSH key successfully downloaded to: {}", private_key_path.display());
 
     Ok(())
 }
 
-fn sign_key_oidc(config: &Config) -> anyhow::Result<()> {
-    debug!("ssh-key gen-new subcommand");
-    debug!("{:?}", config);
+// Load key and certificate into the running ssh-agent, replacing stale CSCS certificates
+#[cfg(unix)]
+fn add_to_agent(key_files: &KeyFiles) -> anyhow::Result<()> {
+    use crate::agent::AgentClient;
+
+    let private_key = PrivateKey::read_openssh_file(&key_files.private_key)
+        .map_err(|e| anyhow!("Failed to read private key {}: {}", key_files.private_key.display(), e))?;
+    let cert = key_files.read_certificate()?
+        .ok_or_else(|| anyhow!("No certificate found at {}", key_files.certificate.display()))?;
+
+    // The agent drops the key when the certificate expires
+    let remaining = cert.valid_before_time()
+        .duration_since(SystemTime::now())
+        .map_err(|_| anyhow!("Certificate {} is already expired", key_files.certificate.display()))?;
+    let lifetime = u32::try_from(remaining.as_secs()).ok();
+
+    let mut agent = AgentClient::from_env()?;
+    let comment = format!("{} {}", AGENT_COMMENT_PREFIX, key_files.certificate.display());
+    agent.add_certificate(&private_key, &cert, &comment, lifetime)?;
+    println!("Added certificate {} to ssh-agent (lifetime {}).", key_files.certificate.display(), format_duration(&remaining));
+
+    // Older certificates of this key are superseded. Other certificates, possibly of another
+    // profile or loaded by the user, are only removed once expired.
+    let cert_blob = cert.to_bytes()?;
+    let ca_fingerprint = cert.signature_key().fingerprint(HashAlg::Sha256);
+    for identity in agent.identities()? {
+        if identity.key_blob == cert_blob {
+            continue;
+        }
+        let Ok(old_cert) = Certificate::from_bytes(&identity.key_blob) else {
+            continue; // Plain keys are never ours to remove
+        };
+        let same_key = old_cert.public_key() == cert.public_key();
+        let expired_cscs = cert_status(&old_cert) == CertStatus::Expired
+            && (identity.comment.starts_with(AGENT_COMMENT_PREFIX)
+                || old_cert.signature_key().fingerprint(HashAlg::Sha256) == ca_fingerprint);
+        if same_key || expired_cscs {
+            info!("Removing stale certificate {} ({}) from ssh-agent", old_cert.serial(), identity.comment);
+            agent.remove(&identity.key_blob)?;
+        }
+    }
 
-    let private_key_path = config.key_path.clone();
-    let public_key_path = PathBuf::from(format!("{}-signing.pub", private_key_path.display()));
-    info!("Reading public key in {}", public_key_path.display());
-    let content = fs::read_to_string(public_key_path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn add_to_agent(_key_files: &KeyFiles) -> anyhow::Result<()> {
+    bail!("Adding certificates to ssh-agent is only supported on Unix-like systems");
+}
+
+// Remember the issued certificate in the state file
+fn record_certificate(config: &Config, key_files: &KeyFiles, certificate: &str, expire_time: &str) -> anyhow::Result<()> {
+    let serial_number = match Certificate::from_openssh(certificate.trim()) {
+        Ok(cert) => cert.serial().to_string(),
+        Err(e) => {
+            warn!("Could not parse issued certificate: {}", e);
+            "unknown".to_string()
+        }
+    };
 
+    AppState::update(|state| state.record_cert(CertMetadata {
+        key_path: key_files.private_key.clone(),
+        cert_path: key_files.certificate.clone(),
+        serial_number,
+        expires_at: expire_time.to_string(),
+        issued_at: Utc::now(),
+        flow: key_files.flow.to_string(),
+        profile: config.profile.clone(),
+        revoked: false,
+    }))
+}
+
+/// Have the SSH service sign `public_key` (OpenSSH format) with the configured validity
+pub fn request_certificate(config: &Config, client: &HttpClient, public_key: &str) -> anyhow::Result<SshKeyCertNew> {
     let public_key = PublicKey {
-        public_key: content,
+        public_key: public_key.to_string(),
         duration: config.key_validity.clone(),
     };
 
     info!("Get OIDC token");
 
-    let access_token = get_access_token(&config)?;
+    // Signing the same public key again is harmless
+    let response = send_authenticated(config, client, true, |access_token| {
+        client.post(config.sign_url.clone())
+            .bearer_auth(access_token)
+            .json(&public_key)
+    })?;
 
-    let client = reqwest::blocking::Client::new();
+    let response_struct: SshserviceResponseCertNew = parse_json(response)
+        .context("Failed to sign SSH key")?;
+    debug!("{:?}", response_struct);
 
-    let response = client.post(config.sign_url.clone())
-        .bearer_auth(&access_token)
-        .json(&public_key)
-        .send()?;
+    Ok(response_struct.ssh_key)
+}
 
-    if !response.status().is_success() {
-        //let error_text = response.text().unwrap_or_else(|_| "Failed to read error response".to_string());
-        //bail!("Failed to download SSH key. HTTP status: {}. Response: {}", response.status(), error_text);
+// Make sure a local key pair exists for signing, generating a new one if either half is missing
+fn ensure_signing_key(key_files: &KeyFiles, key_type: &str) -> anyhow::Result<()> {
+    let public_key_path = key_files.public_key.as_ref().unwrap();
+    // The private half may live elsewhere, e.g. in a hardware token or the agent
+    if public_key_path.exists() {
+        return Ok(());
     }
 
-    //debug!("response: {:?}", response);
-    //debug!("response.text: {:?}", response.text()?);
+    if key_files.private_key.exists() {
+        // Recover the missing public half from the existing private key
+        info!("Deriving public key from {}", key_files.private_key.display());
+        let private_key = PrivateKey::read_openssh_file(&key_files.private_key)
+            .map_err(|e| anyhow!("Failed to read private key {}: {}", key_files.private_key.display(), e))?;
+        let public_key = format!("{}\n", private_key.public_key().to_openssh()?);
+        return files::write_atomic(public_key_path, public_key.as_bytes(), files::PUBLIC);
+    }
 
-    let response_struct: SshserviceResponseCertNew = response.json()?;
-    //let response_struct = response.text()?;
-    debug!("{:?}", response_struct);
+    info!("Generating new {} key pair", key_type);
+    let mut private_key = generate_keypair(key_type)?;
+    private_key.set_comment("cscs-key");
 
-    let private_key_path = config.key_path.clone();
-    let public_key_path = PathBuf::from(format!("{}-signing-cert.pub", private_key_path.display()));
+    // Neither file exists, so nothing is overwritten
+    info!("Saving private key in {}", key_files.private_key.display());
+    let public_key = format!("{}\n", private_key.public_key().to_openssh()?);
+    files::write_pair(
+        (&key_files.private_key, private_key.to_openssh(LineEnding::LF)?.as_bytes(), files::PRIVATE),
+        (public_key_path, public_key.as_bytes(), files::PUBLIC),
+    )?;
+    println!("Generated new {} key pair: {}", key_type, key_files.private_key.display());
 
-    if let Some(parent) = private_key_path.parent() {
-        std::fs::create_dir_all(parent)?;
+    Ok(())
+}
+
+pub fn generate_keypair(key_type: &str) -> anyhow::Result<PrivateKey> {
+    let private_key = match key_type {
+        "ed25519" => PrivateKey::random(&mut OsRng, Algorithm::Ed25519)?,
+        "ecdsa" | "ecdsa-p256" => PrivateKey::random(&mut OsRng, Algorithm::Ecdsa { curve: EcdsaCurve::NistP256 })?,
+        "ecdsa-p384" => PrivateKey::random(&mut OsRng, Algorithm::Ecdsa { curve: EcdsaCurve::NistP384 })?,
+        "rsa" | "rsa-3072" => PrivateKey::new(RsaKeypair::random(&mut OsRng, 3072)?.into(), "")?,
+        "rsa-4096" => PrivateKey::new(RsaKeypair::random(&mut OsRng, 4096)?.into(), "")?,
+        other => bail!("Unsupported key type '{}'. Use ed25519, ecdsa-p256, ecdsa-p384, rsa-3072 or rsa-4096.", other),
+    };
+
+    Ok(private_key)
+}
+
+// Serialize key and certificate updates of concurrent invocations for the same key path
+fn lock_keys(config: &Config) -> anyhow::Result<files::FileLock> {
+    info!("Acquiring key lock {}.lock", config.key_path.display());
+    files::lock(&config.key_path)
+}
+
+// Issue a new certificate through the flow selected by `Config::issue_method`
+fn issue_key(config: &Config, client: &HttpClient) -> anyhow::Result<()> {
+    match KeyFiles::configured(config)?.flow {
+        "gen-oidc" => download_key_oidc(config, client),
+        _ => sign_key_oidc(config, client),
     }
+}
 
-    // Save public key
-    let mut public_file = File::create(&public_key_path)?;
-    info!("Saving public key in {}", public_key_path.display());
-    public_file.write_all(response_struct.ssh_key.public_key.as_bytes())?;
-    #[cfg(unix)] // Only apply on Unix-like systems
+pub fn ensure_key(config: &Config, client: &HttpClient) -> anyhow::Result<()> {
+    debug!("ssh-key ensure subcommand");
+    debug!("{:?}", config);
+
+    let margin = duration_str::parse(&config.renew_margin)
+        .map_err(|e| anyhow!("Invalid renewal margin '{}': {}", config.renew_margin, e))?;
+    let key_files = KeyFiles::configured(config)?;
+
+    // Fast path: nothing to do, no locking and no network
+    if key_files.is_valid_for(margin) {
+        info!("Certificate {} is valid for at least {}", key_files.certificate.display(), config.renew_margin);
+        return Ok(());
+    }
+
+    if let Ok(validity) = duration_str::parse(&config.key_validity)
+        && validity <= margin
     {
-        info!("Setting permissions for public key to 0o644: {}", public_key_path.display());
-        use std::os::unix::fs::PermissionsExt;
-        let mut permissions = public_file.metadata()?.permissions();
-        permissions.set_mode(0o644); // Read/write for owner only
-        std::fs::set_permissions(&public_key_path, permissions)?;
+        eprintln!("Warning: key validity ({}) does not exceed the renewal margin ({}), the certificate will be renewed on every call.",
+            config.key_validity, config.renew_margin);
     }
-    info!("Public SSH key successfully downloaded to {}", public_key_path.display());
 
-    Ok(())
+    // Only one process renews, concurrent invocations wait for it and reuse the result
+    let _lock = lock_keys(config)?;
+
+    if key_files.is_valid_for(margin) {
+        info!("Certificate was renewed by another process");
+        return Ok(());
+    }
+
+    eprintln!("Renewing SSH certificate {}", key_files.certificate.display());
+    issue_key(config, client)
 }
 
 fn status_key(config: &Config) -> anyhow::Result<()> {
     debug!("ssh-key status subcommand");
     debug!("{:?}", config);
 
-    let metadata_result = metadata(&config.key_path);
-    let file_metadata = match metadata_result {
-        Ok(meta) => {
-            if meta.is_file() {
-                info!("SSH key file found at: {}", &config.key_path.display());
-                meta
-            } else {
-                bail!("Path '{}' exists but is not a file (it's a directory or other type).", &config.key_path.display());
-            }
-        },
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-            bail!("SSH key file not found at: {}. Please run 'ssh-key download'.", &config.key_path.display());
-        },
-        Err(e) => {
-            bail!("Error accessing SSH key file at {}: {}", &config.key_path.display(), e);
+    let mut found = false;
+    let mut valid = false;
+    for key_files in [KeyFiles::generated(config), KeyFiles::signed(config)] {
+        let Some(cert) = key_files.read_certificate()? else {
+            info!("No certificate found at {}", key_files.certificate.display());
+            continue;
+        };
+        if found {
+            println!();
         }
-    };
+        found = true;
+        valid |= cert_status(&cert) == CertStatus::Valid;
+        print_certificate(&key_files, &cert);
+    }
 
-    debug!("{:?}", file_metadata);
-    let modified_time = file_metadata.modified()?;
-    let now = SystemTime::now();
-    let duration_since_modified = now.duration_since(modified_time)
-        .map_err(|e| anyhow!("System time is earlier than file modification time: {}", e))?;
+    if !found {
+        if let Some(cert) = AppState::load()?.latest_cert(&config.profile) {
+            println!("Last issued certificate {} ({}) was saved to {} and expires at {}.",
+                cert.serial_number, cert.flow, cert.cert_path.display(), cert.expires_at);
+        }
+        bail!("No SSH certificate found for {}. Please run 'cscs-key sign-oidc' or 'cscs-key gen-oidc'.", config.key_path.display());
+    }
+    if !valid {
+        bail!("SSH certificate is not valid. Please run 'cscs-key sign-oidc' or 'cscs-key gen-oidc' to renew.");
+    }
 
-    let validity = duration_str::parse(config.key_validity.clone()).unwrap();
+    Ok(())
+}
 
-    if duration_since_modified > validity {
-        println!("SSH key is EXPIRED (last modified {} ago).",
-            format_duration(&duration_since_modified));
-        bail!("SSH key is expired. Please run 'ssh-key download' to renew.");
+#[derive(Debug, PartialEq, Eq)]
+enum CertStatus {
+    NotYetValid,
+    Valid,
+    Expired,
+}
+
+fn cert_status(cert: &Certificate) -> CertStatus {
+    let now = SystemTime::now();
+    if now < cert.valid_after_time() {
+        CertStatus::NotYetValid
+    } else if now >= cert.valid_before_time() {
+        CertStatus::Expired
     } else {
-        println!("SSH key is VALID (last modified {} ago).",
-            format_duration(&duration_since_modified));
+        CertStatus::Valid
     }
+}
 
-    Ok(())
+fn format_timestamp(timestamp: u64) -> String {
+    i64::try_from(timestamp).ok()
+        .and_then(|secs| DateTime::<Utc>::from_timestamp(secs, 0))
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+fn format_options(options: &ssh_key::certificate::OptionsMap) -> String {
+    if options.is_empty() {
+        return "(none)".to_string();
+    }
+    options.iter()
+        .map(|(name, value)| if value.is_empty() { name.clone() } else { format!("{}={}", name, value) })
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
-fn list_keys(config: &Config) -> anyhow::Result<()> {
+fn print_certificate(key_files: &KeyFiles, cert: &Certificate) {
+    let now = SystemTime::now();
+    let status = match cert_status(cert) {
+        CertStatus::NotYetValid => {
+            let starts_in = cert.valid_after_time().duration_since(now).unwrap_or_default();
+            format!("NOT YET VALID (valid in {})", format_duration(&starts_in))
+        }
+        CertStatus::Expired => {
+            let expired_since = now.duration_since(cert.valid_before_time()).unwrap_or_default();
+            format!("EXPIRED ({} ago)", format_duration(&expired_since))
+        }
+        CertStatus::Valid => {
+            let remaining = cert.valid_before_time().duration_since(now).unwrap_or_default();
+            format!("VALID (expires in {})", format_duration(&remaining))
+        }
+    };
+
+    println!("Certificate:      {} ({})", key_files.certificate.display(), key_files.flow);
+    println!("Status:           {}", status);
+    let cert_type = if cert.cert_type().is_host() { "host" } else { "user" };
+    println!("Type:             {} {} certificate", cert.algorithm(), cert_type);
+    println!("Serial:           {}", cert.serial());
+    println!("Key ID:           {}", cert.key_id());
+    println!("Principals:       {}", cert.valid_principals().join(", "));
+    println!("Valid after:      {}", format_timestamp(cert.valid_after()));
+    println!("Valid before:     {}", format_timestamp(cert.valid_before()));
+    println!("Critical options: {}", format_options(cert.critical_options()));
+    println!("Extensions:       {}", format_options(cert.extensions()));
+    println!("Signing CA:       {} {}", cert.signature_key().algorithm(), cert.signature_key().fingerprint(HashAlg::Sha256));
+}
+
+fn list_keys(config: &Config, client: &HttpClient) -> anyhow::Result<()> {
     debug!("ssh-key list subcommand");
     debug!("{:?}", config);
 
-    todo!("ssh-key list");
+    info!("Get OIDC token");
+
+    let keys = fetch_keys(config, client)?;
+
+    if keys.is_empty() {
+        println!("No SSH keys have been issued.");
+        return Ok(());
+    }
+
+    print_keys(&keys);
+
+    let valid = keys.iter().filter(|key| !key.is_expired()).count();
+    println!();
+    println!("{} keys issued, {} still valid.", keys.len(), valid);
 
-    //Ok(())
+    Ok(())
 }
 
-fn revoke_keys(config: &Config) -> anyhow::Result<()> {
+fn list_local_certs() -> anyhow::Result<()> {
+    let state = AppState::load()?;
+    if state.ssh_certs.is_empty() {
+        println!("No SSH certificates have been recorded.");
+        return Ok(());
+    }
+
+    let now = Utc::now();
+    let header = ["SERIAL", "ISSUED", "EXPIRES", "STATUS", "PROFILE", "FLOW", "CERTIFICATE"];
+    let rows: Vec<[String; 7]> = state.ssh_certs.iter()
+        .rev()
+        .map(|cert| {
+            let expired = DateTime::parse_from_rfc3339(&cert.expires_at)
+                .map(|expire_time| expire_time <= now)
+                .unwrap_or(true);
+            let status = if cert.revoked { "REVOKED" } else if expired { "EXPIRED" } else { "VALID" };
+            [
+                cert.serial_number.clone(),
+                cert.issued_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                cert.expires_at.clone(),
+                status.to_string(),
+                cert.profile.clone(),
+                cert.flow.clone(),
+                cert.cert_path.display().to_string(),
+            ]
+        })
+        .collect();
+    print_table(&header, &rows);
+
+    Ok(())
+}
+
+// Retrieve all keys issued to the user, following the pagination tokens
+fn fetch_keys(config: &Config, client: &HttpClient) -> anyhow::Result<Vec<SshKeyInfo>> {
+    let mut keys = Vec::new();
+    let mut page_token: Option<String> = None;
+
+    loop {
+        let response = send_authenticated(config, client, true, |access_token| {
+            let request = client.get(config.keys_url.clone())
+                .bearer_auth(access_token);
+            match &page_token {
+                Some(token) => request.query(&[("pageToken", token)]),
+                None => request,
+            }
+        })?;
+
+        let response_struct: SshserviceResponseList = parse_json(response)
+            .context("Failed to list SSH keys")?;
+        debug!("{:?}", response_struct);
+        keys.extend(response_struct.ssh_keys);
+
+        match response_struct.next_page_token {
+            Some(token) if !token.is_empty() => page_token = Some(token),
+            _ => break,
+        }
+    }
+
+    Ok(keys)
+}
+
+fn print_table<const N: usize>(header: &[&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(|h| h.len());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let format_row = |cells: Vec<&str>| {
+        cells.iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    println!("{}", format_row(header.to_vec()));
+    for row in rows {
+        println!("{}", format_row(row.iter().map(String::as_str).collect()));
+    }
+}
+
+fn revoke_keys(config: &Config, client: &HttpClient, args: &RevokeArgs) -> anyhow::Result<()> {
     debug!("ssh-key revoke subcommand");
     debug!("{:?}", config);
+    debug!("{:?}", args);
+
+    let local_keys = [KeyFiles::generated(config), KeyFiles::signed(config)];
+    let local_identities: Vec<(String, String)> = local_keys.iter()
+        .filter_map(KeyFiles::identity)
+        .collect();
+    if args.current && local_identities.is_empty() {
+        bail!("No certificate found at {}. Nothing to revoke as current key.", config.key_path.display());
+    }
 
-    todo!("ssh-key revoke");
+    info!("Get OIDC token");
+
+    let keys = fetch_keys(config, client)?;
+
+    let is_current = |key: &SshKeyInfo| {
+        local_identities.iter()
+            .any(|(serial, fingerprint)| *serial == key.serial_number || *fingerprint == key.fingerprint)
+    };
+    let selected: Vec<&SshKeyInfo> = keys.iter()
+        .filter(|key| {
+            args.all
+                || args.serial.contains(&key.serial_number)
+                || args.fingerprint.contains(&key.fingerprint)
+                || (args.expired && key.is_expired())
+                || (args.current && is_current(key))
+        })
+        .collect();
+
+    for serial in &args.serial {
+        if !keys.iter().any(|key| key.serial_number == *serial) {
+            eprintln!("No SSH key with serial number {} found.", serial);
+        }
+    }
+    for fingerprint in &args.fingerprint {
+        if !keys.iter().any(|key| key.fingerprint == *fingerprint) {
+            eprintln!("No SSH key with fingerprint {} found.", fingerprint);
+        }
+    }
+
+    if selected.is_empty() {
+        println!("No matching SSH keys to revoke.");
+        return Ok(());
+    }
+
+    println!("The following SSH keys will be revoked:");
+    print_keys(selected.iter().copied());
+    println!();
+
+    if !args.yes && !confirm(&format!("Revoke {} key(s)?", selected.len()))? {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    let mut revoked = Vec::new();
+    let mut failed = 0;
+    let mut aborted = None;
+    for key in selected {
+        let url = format!("{}/{}", config.keys_url.trim_end_matches('/'), key.serial_number);
+        info!("Revoking SSH key {} via {}", key.serial_number, url);
+        let response = send_authenticated(config, client, true, |access_token| {
+            client.delete(&url)
+                .bearer_auth(access_token)
+        });
+
+        match response.and_then(|response| Ok(check_response(response)?)) {
+            Ok(_) => {
+                println!("Revoked SSH key {}.", key.serial_number);
+                revoked.push(key);
+            }
+            // Network failures and a rejected token affect every remaining key alike
+            Err(e) if e.downcast_ref::<ApiError>().is_none_or(|e| matches!(e, ApiError::Unauthorized { .. })) => {
+                aborted = Some(e);
+                break;
+            }
+            Err(e) => {
+                eprintln!("Failed to revoke SSH key {}. {}", key.serial_number, e);
+                failed += 1;
+            }
+        }
+    }
+
+    // Mark the locally recorded certificates as revoked, including those revoked before an abort
+    if !revoked.is_empty() {
+        AppState::update(|state| {
+            for cert in state.ssh_certs.iter_mut() {
+                if !cert.revoked && revoked.iter().any(|key| key.serial_number == cert.serial_number) {
+                    info!("Marking certificate {} as revoked in state", cert.serial_number);
+                    cert.revoked = true;
+                }
+            }
+        })?;
+    }
+
+    if args.delete_files {
+        let _lock = lock_keys(config)?;
+        for key_files in &local_keys {
+            let Some((serial, fingerprint)) = key_files.identity() else {
+                continue;
+            };
+            if !revoked.iter().any(|key| key.serial_number == serial || key.fingerprint == fingerprint) {
+                continue;
+            }
+            for path in key_files.all() {
+                if path.exists() {
+                    fs::remove_file(path)?;
+                    println!("Deleted {}", path.display());
+                }
+            }
+        }
+    }
+
+    if let Some(e) = aborted {
+        return Err(e).context("Failed to revoke SSH keys");
+    }
+    if failed > 0 {
+        bail!("Failed to revoke {} SSH key(s).", failed);
+    }
+
+    Ok(())
+}
+
+fn confirm(prompt: &str) -> anyhow::Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        bail!("Refusing to continue without confirmation on a non-interactive terminal. Use --yes to skip the prompt.");
+    }
+
+    print!("{} [y/N] ", prompt);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
 
-    //Ok(())
+fn print_keys<'a>(keys: impl IntoIterator<Item = &'a SshKeyInfo>) {
+    let header = ["SERIAL", "FINGERPRINT", "CREATED", "EXPIRES", "STATUS", "COMMENT"];
+    let rows: Vec<[String; 6]> = keys.into_iter()
+        .map(|key| [
+            key.serial_number.clone(),
+            key.fingerprint.clone(),
+            key.create_time.clone(),
+            key.expire_time.clone(),
+            if key.is_expired() { "EXPIRED" } else { "VALID" }.to_string(),
+            key.comment.clone(),
+        ])
+        .collect();
+    print_table(&header, &rows);
 }
 
-fn format_duration(duration: &std::time::Duration) -> String {
+pub fn format_duration(duration: &std::time::Duration) -> String {
     let secs = duration.as_secs();
     if secs < 60 {
         format!("{} seconds", secs)
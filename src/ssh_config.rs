@@ -0,0 +1,244 @@
+use clap::Args;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context};
+use log::{info, debug};
+
+use crate::config::Config;
+use crate::files;
+use crate::ssh::KeyFiles;
+
+const BEGIN_MARKER: &str = "# BEGIN cscs-key managed section (generated, do not edit)";
+const END_MARKER: &str = "# END cscs-key managed section";
+
+#[derive(Args, Debug)]
+pub struct SshConfigArgs {
+    #[arg(long, value_name = "PATH", help = "File to write [default: ~/.ssh/config.d/cscs]")]
+    output: Option<PathBuf>,
+    #[arg(long, help = "Renew the certificate automatically before connecting (ssh `Match exec` hook)")]
+    renew_hook: bool,
+    #[arg(long, help = "Print the managed section instead of writing it")]
+    dry_run: bool,
+}
+
+pub fn write_ssh_config(config: &Config, args: &SshConfigArgs) -> anyhow::Result<()> {
+    debug!("ssh-key ssh-config subcommand");
+    debug!("{:?}", config);
+
+    let ssh_dir = dirs::home_dir()
+        .context("Could not determine home directory")?
+        .join(".ssh");
+    let output = args.output.clone()
+        .unwrap_or_else(|| ssh_dir.join("config.d").join("cscs"));
+
+    let section = managed_section(config, args.renew_hook)?;
+    if args.dry_run {
+        print!("{}", section);
+        return Ok(());
+    }
+
+    // Concurrent invocations must not lose each other's update
+    let _lock = files::lock(&output)?;
+    let existing = match fs::read_to_string(&output) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", output.display())),
+    };
+    let updated = replace_section(&existing, &section)?;
+
+    if updated == existing {
+        println!("SSH config {} is up to date.", output.display());
+    } else {
+        info!("Writing ssh config to {}", output.display());
+        files::write_atomic(&output, updated.as_bytes(), files::PRIVATE)?;
+        println!("SSH config written to {}", output.display());
+    }
+
+    // The include only takes effect if the main config pulls it in
+    let main_config = ssh_dir.join("config");
+    let included = fs::read_to_string(&main_config)
+        .map(|content| content.lines().any(|line| is_include_of(line, &output, &ssh_dir)))
+        .unwrap_or(false);
+    if !included {
+        println!("Add the following line at the top of {} to use it:", main_config.display());
+        println!("    Include {}", output.display());
+    }
+
+    Ok(())
+}
+
+fn managed_section(config: &Config, renew_hook: bool) -> anyhow::Result<String> {
+    let key_files = KeyFiles::configured(config)?;
+    let identity_file = quote(&key_files.private_key);
+    let certificate_file = quote(&key_files.certificate);
+    let user = config.username.as_ref().map(|user| format!("    User {}\n", user)).unwrap_or_default();
+
+    let mut section = String::new();
+    section.push_str(BEGIN_MARKER);
+    section.push('\n');
+
+    if renew_hook {
+        // Evaluated while parsing the config, before any connection is made
+        let exe = std::env::current_exe().context("Could not determine the cscs-key executable")?;
+        let mut words = vec![exe.display().to_string(), "ensure".to_string()];
+        words.extend(config.selection_args());
+        let command = exec_command(&words)?;
+        // Match is evaluated before HostName is applied, so include the aliases
+        let hosts: Vec<&str> = std::iter::once(&config.jump_host)
+            .chain(config.clusters.iter())
+            .flat_map(|host| [short_name(host), host.as_str()])
+            .collect();
+        section.push_str(&format!("Match host {} exec \"{}\"\n\n", hosts.join(","), command));
+    }
+
+    section.push_str(&format!("Host {} {}\n", short_name(&config.jump_host), config.jump_host));
+    section.push_str(&format!("    HostName {}\n", config.jump_host));
+    section.push_str(&user);
+    section.push_str(&format!("    IdentityFile {}\n", identity_file));
+    section.push_str(&format!("    CertificateFile {}\n", certificate_file));
+    section.push_str("    IdentitiesOnly yes\n");
+
+    for cluster in &config.clusters {
+        section.push('\n');
+        section.push_str(&format!("Host {} {}\n", short_name(cluster), cluster));
+        section.push_str(&format!("    HostName {}\n", cluster));
+        section.push_str(&user);
+        section.push_str(&format!("    ProxyJump {}\n", config.jump_host));
+        section.push_str(&format!("    IdentityFile {}\n", identity_file));
+        section.push_str(&format!("    CertificateFile {}\n", certificate_file));
+        section.push_str("    IdentitiesOnly yes\n");
+    }
+
+    section.push_str(END_MARKER);
+    section.push('\n');
+
+    Ok(section)
+}
+
+// Replace the managed section in `existing`, or append it, keeping everything else untouched
+fn replace_section(existing: &str, section: &str) -> anyhow::Result<String> {
+    let begin = existing.find(BEGIN_MARKER);
+    let end = existing.find(END_MARKER);
+
+    match (begin, end) {
+        (Some(begin), Some(end)) if begin < end => {
+            let end = end + END_MARKER.len();
+            let rest = existing[end..].strip_prefix('\n').unwrap_or(&existing[end..]);
+            Ok(format!("{}{}{}", &existing[..begin], section, rest))
+        }
+        (None, None) if existing.is_empty() => Ok(section.to_string()),
+        (None, None) => {
+            let separator = if existing.ends_with('\n') { "\n" } else { "\n\n" };
+            Ok(format!("{}{}{}", existing, separator, section))
+        }
+        _ => bail!("The managed section markers in the ssh config are damaged. Please remove the cscs-key section manually."),
+    }
+}
+
+fn short_name(host: &str) -> &str {
+    host.split('.').next().unwrap_or(host)
+}
+
+// IdentityFile and CertificateFile expand % tokens, so a literal % is written as %%
+fn quote(path: &Path) -> String {
+    let path = path.display().to_string().replace('%', "%%");
+    if path.contains(char::is_whitespace) {
+        format!("\"{}\"", path)
+    } else {
+        path
+    }
+}
+
+// The `Match exec` command is run by the shell after ssh expanded its % tokens. ssh versions
+// disagree on backslash escapes inside the quoted argument, so words are single-quoted for the
+// shell and quotes or backslashes cannot be represented at all.
+fn exec_command(words: &[String]) -> anyhow::Result<String> {
+    let mut quoted = Vec::new();
+    for word in words {
+        if word.contains(['\'', '"', '\\']) {
+            bail!("Cannot use '{}' in the ssh config renew hook, it contains a quote or backslash", word);
+        }
+        let safe = !word.is_empty()
+            && word.chars().all(|c| c.is_ascii_alphanumeric() || "/._+-=:,@%".contains(c));
+        let word = if safe { word.clone() } else { format!("'{}'", word) };
+        quoted.push(word.replace('%', "%%"));
+    }
+    Ok(quoted.join(" "))
+}
+
+fn is_include_of(line: &str, output: &Path, ssh_dir: &Path) -> bool {
+    let mut words = line.split_whitespace();
+    if !words.next().is_some_and(|word| word.eq_ignore_ascii_case("include")) {
+        return false;
+    }
+
+    let output = output.display().to_string();
+    words.any(|pattern| {
+        let pattern = pattern.trim_matches('"');
+        // Relative includes are resolved against ~/.ssh
+        let pattern = match pattern.strip_prefix("~/") {
+            Some(rest) => dirs::home_dir().map(|home| home.join(rest)).unwrap_or_default(),
+            None => ssh_dir.join(pattern),
+        };
+        let pattern = pattern.display().to_string();
+        pattern == output || pattern.strip_suffix('*').is_some_and(|prefix| output.starts_with(prefix))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(body: &str) -> String {
+        format!("{}\n{}{}\n", BEGIN_MARKER, body, END_MARKER)
+    }
+
+    #[test]
+    fn replace_section_writes_new_file() {
+        let new = section("Host daint\n");
+        assert_eq!(replace_section("", &new).unwrap(), new);
+    }
+
+    #[test]
+    fn replace_section_appends_after_existing_content() {
+        let new = section("Host daint\n");
+        assert_eq!(replace_section("Host other\n", &new).unwrap(), format!("Host other\n\n{}", new));
+        assert_eq!(replace_section("Host other", &new).unwrap(), format!("Host other\n\n{}", new));
+    }
+
+    #[test]
+    fn replace_section_keeps_surrounding_content() {
+        let old = format!("Host before\n\n{}Host after\n", section("Host daint\n"));
+        let new = section("Host eiger\n");
+        let updated = replace_section(&old, &new).unwrap();
+        assert_eq!(updated, format!("Host before\n\n{}Host after\n", new));
+        assert_eq!(replace_section(&updated, &new).unwrap(), updated);
+    }
+
+    #[test]
+    fn replace_section_rejects_damaged_markers() {
+        let new = section("Host daint\n");
+        assert!(replace_section(&format!("{}\nHost daint\n", BEGIN_MARKER), &new).is_err());
+        assert!(replace_section(&format!("Host daint\n{}\n", END_MARKER), &new).is_err());
+        assert!(replace_section(&format!("{}\n{}\n", END_MARKER, BEGIN_MARKER), &new).is_err());
+    }
+
+    #[test]
+    fn exec_command_quotes_words_for_shell_and_ssh() {
+        let words = |words: &[&str]| words.iter().map(|word| word.to_string()).collect::<Vec<_>>();
+        assert_eq!(exec_command(&words(&["/usr/bin/cscs-key", "ensure", "--profile", "dev"])).unwrap(),
+            "/usr/bin/cscs-key ensure --profile dev");
+        assert_eq!(exec_command(&words(&["/home/a b/cscs-key", "ensure", "--key-path", "/tmp/100%"])).unwrap(),
+            "'/home/a b/cscs-key' ensure --key-path /tmp/100%%");
+        assert!(exec_command(&words(&["/home/it's/cscs-key"])).is_err());
+        assert!(exec_command(&words(&["/home/\"x\"/cscs-key"])).is_err());
+    }
+
+    #[test]
+    fn quote_escapes_percent_and_wraps_spaces() {
+        assert_eq!(quote(Path::new("/home/user/.ssh/cscs-key")), "/home/user/.ssh/cscs-key");
+        assert_eq!(quote(Path::new("/home/a b/.ssh/cscs-key")), "\"/home/a b/.ssh/cscs-key\"");
+        assert_eq!(quote(Path::new("/tmp/100%/cscs-key")), "/tmp/100%%/cscs-key");
+        assert_eq!(quote(Path::new("/tmp/100% a/key")), "\"/tmp/100%% a/key\"");
+    }
+}
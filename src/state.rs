@@ -1,18 +1,31 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
-use directories::ProjectDirs;
 use std::fs;
 use serde::{Serialize, Deserialize};
-use serde_json;
+use serde::de::DeserializeOwned;
 use chrono::{DateTime, Utc, Duration};
 use log::info;
 
+use crate::config::DEFAULT_PROFILE;
+use crate::files;
+
+/// Number of issued certificates kept in the state file
+const MAX_CERT_HISTORY: usize = 20;
+
 #[derive(Serialize, Deserialize, Default)]
 pub struct AppState {
-    pub oidc_token: Option<TokenStore>,
-    pub ssh_cert: Option<CertMetadata>,
+    /// Token of a state file written before profiles existed, belongs to the default profile
+    #[serde(default, skip_serializing)]
+    oidc_token: Option<TokenStore>,
+    /// Cached tokens by profile name
+    #[serde(default)]
+    pub oidc_tokens: BTreeMap<String, TokenStore>,
+    /// Issued certificates, oldest first
+    #[serde(default)]
+    pub ssh_certs: Vec<CertMetadata>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TokenStore {
     pub access_token: String,
     pub refresh_token: Option<String>,
@@ -20,21 +33,83 @@ pub struct TokenStore {
     pub expiration: Option<DateTime<Utc>>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Service-account tokens of the file storage by API key fingerprint, kept apart from the interactive tokens in `AppState`
+#[derive(Serialize, Deserialize, Default)]
+pub struct ServiceTokenCache {
+    #[serde(default)]
+    pub tokens: BTreeMap<String, ServiceToken>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceToken {
+    /// Token endpoint that issued the token
+    pub token_url: String,
+    #[serde(flatten)]
+    pub token: TokenStore,
+}
+
+/// OpenID provider documents by issuer URL
+#[derive(Serialize, Deserialize, Default)]
+pub struct DiscoveryCache {
+    #[serde(default)]
+    pub providers: BTreeMap<String, ProviderDocuments>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProviderDocuments {
+    /// Last time both documents were fetched or revalidated
+    pub fetched_at: DateTime<Utc>,
+    pub metadata: DiscoveryDocument,
+    pub jwks: DiscoveryDocument,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DiscoveryDocument {
+    pub url: String,
+    pub etag: Option<String>,
+    pub content: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct CertMetadata {
     pub key_path: PathBuf,
     pub cert_path: PathBuf,
     pub serial_number: String,
+    /// Expiration time as reported by the SSH service
     pub expires_at: String,
+    pub issued_at: DateTime<Utc>,
+    /// Subcommand that issued the certificate (`gen-oidc` or `sign-oidc`)
+    pub flow: String,
+    /// Configuration profile that issued the certificate, records from before profiles belong to the default one
+    #[serde(default = "default_profile")]
+    pub profile: String,
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+pub fn cache_path(file_name: &str) -> anyhow::Result<PathBuf> {
+    let cache_dir = cache_dir()?;
+    files::create_dir(&cache_dir)?;
+    Ok(cache_dir.join(file_name))
+}
+
+#[cfg(not(test))]
+fn cache_dir() -> anyhow::Result<PathBuf> {
+    let proj_dirs = directories::ProjectDirs::from("ch", "cscs", "cscs-key")
+        .ok_or_else(|| anyhow::anyhow!("Could not determine home directory"))?;
+    Ok(proj_dirs.cache_dir().to_path_buf())
+}
+
+// Tests must not touch the tokens and certificates of the user running them
+#[cfg(test)]
+fn cache_dir() -> anyhow::Result<PathBuf> {
+    tests::CACHE_DIR.lock().unwrap().clone()
+        .ok_or_else(|| anyhow::anyhow!("Tests using the cache must hold a TestCacheDir"))
 }
 
 impl AppState {
     fn get_path() -> anyhow::Result<PathBuf> {
-        let proj_dirs = ProjectDirs::from("ch", "cscs", "cscs-key")
-            .ok_or_else(|| anyhow::anyhow!("Could not determine home directory"))?;
-        let cache_dir = proj_dirs.cache_dir();
-        fs::create_dir_all(cache_dir)?;
-        Ok(cache_dir.join("token.json"))
+        cache_path("token.json")
     }
 
     pub fn load() -> anyhow::Result<Self> {
@@ -44,24 +119,195 @@ impl AppState {
             return Ok(Self::default());
         }
         let content = fs::read_to_string(path)?;
-        Ok(serde_json::from_str(&content)?)
+        let mut state: Self = serde_json::from_str(&content)?;
+        if let Some(token) = state.oidc_token.take() {
+            state.oidc_tokens.entry(DEFAULT_PROFILE.to_string()).or_insert(token);
+        }
+        Ok(state)
     }
 
     pub fn save(&self) -> anyhow::Result<()> {
         let path = Self::get_path()?;
         info!("Saving state to {}", path.display());
         let json = serde_json::to_string_pretty(self)?;
-        fs::write(path, json)?;
-        Ok(())
+        files::write_atomic(&path, json.as_bytes(), files::PRIVATE)
+    }
+
+    /// Load, modify and save the state while holding its lock, so concurrent updates are not lost
+    pub fn update<R>(f: impl FnOnce(&mut Self) -> R) -> anyhow::Result<R> {
+        let _lock = files::lock(&Self::get_path()?)?;
+        let mut state = Self::load()?;
+        let result = f(&mut state);
+        state.save()?;
+        Ok(result)
+    }
+}
+
+impl AppState {
+    pub fn record_cert(&mut self, cert: CertMetadata) {
+        self.ssh_certs.push(cert);
+        if self.ssh_certs.len() > MAX_CERT_HISTORY {
+            let excess = self.ssh_certs.len() - MAX_CERT_HISTORY;
+            self.ssh_certs.drain(..excess);
+        }
+    }
+
+    /// Most recently issued certificate of `profile` that has not been revoked
+    pub fn latest_cert(&self, profile: &str) -> Option<&CertMetadata> {
+        self.ssh_certs.iter().rev().find(|cert| !cert.revoked && cert.profile == profile)
     }
 }
 
+fn default_profile() -> String {
+    DEFAULT_PROFILE.to_string()
+}
+
+impl ServiceTokenCache {
+    pub fn load() -> anyhow::Result<Self> {
+        load_json("service-tokens.json")
+    }
+
+    pub fn update<R>(f: impl FnOnce(&mut Self) -> R) -> anyhow::Result<R> {
+        update_json("service-tokens.json", f)
+    }
+}
+
+impl DiscoveryCache {
+    pub fn load() -> anyhow::Result<Self> {
+        load_json("discovery.json")
+    }
+
+    pub fn update<R>(f: impl FnOnce(&mut Self) -> R) -> anyhow::Result<R> {
+        update_json("discovery.json", f)
+    }
+}
+
+fn load_json<T: DeserializeOwned + Default>(file_name: &str) -> anyhow::Result<T> {
+    let path = cache_path(file_name)?;
+    info!("Trying to load {}", path.display());
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let content = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+// Load, modify and save a cache file while holding its lock
+fn update_json<T: Serialize + DeserializeOwned + Default, R>(
+    file_name: &str,
+    f: impl FnOnce(&mut T) -> R,
+) -> anyhow::Result<R> {
+    let path = cache_path(file_name)?;
+    let _lock = files::lock(&path)?;
+    let mut value = load_json(file_name)?;
+    let result = f(&mut value);
+    info!("Saving {}", path.display());
+    let json = serde_json::to_string_pretty(&value)?;
+    files::write_atomic(&path, json.as_bytes(), files::PRIVATE)?;
+    Ok(result)
+}
+
 impl TokenStore {
+    /// Minimum remaining validity of a token that is not considered expired
+    pub fn grace_period() -> Duration {
+        Duration::seconds(10)
+    }
+
     pub fn is_expired(&self) -> bool {
-        let grace_period = Duration::seconds(10);
+        !self.is_valid_for(Self::grace_period())
+    }
+
+    /// Whether the access token remains valid for at least `duration`
+    pub fn is_valid_for(&self, duration: Duration) -> bool {
         match self.expiration {
-            Some(expire_at) => Utc::now() + grace_period > expire_at,
-            None => true,
+            Some(expire_at) => Utc::now() + duration <= expire_at,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+
+    pub static CACHE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+    static CACHE_USER: Mutex<()> = Mutex::new(());
+
+    /// Empty cache directory of a test, removed when dropped. Holding it keeps other tests
+    /// from using the cache at the same time.
+    pub struct TestCacheDir {
+        path: PathBuf,
+        _exclusive: MutexGuard<'static, ()>,
+    }
+
+    impl TestCacheDir {
+        pub fn new() -> Self {
+            // A failed test poisons the mutex, but leaves nothing the next one depends on
+            let exclusive = CACHE_USER.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let path = std::env::temp_dir().join(format!("cscs-key-test-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            *CACHE_DIR.lock().unwrap() = Some(path.clone());
+            Self { path, _exclusive: exclusive }
+        }
+    }
+
+    impl Drop for TestCacheDir {
+        fn drop(&mut self) {
+            *CACHE_DIR.lock().unwrap() = None;
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn token(access_token: &str, expiration: Option<DateTime<Utc>>) -> TokenStore {
+        TokenStore {
+            access_token: access_token.to_string(),
+            refresh_token: None,
+            id_token: None,
+            expiration,
         }
     }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let _cache = TestCacheDir::new();
+        let writers: Vec<_> = (0..8)
+            .map(|i| std::thread::spawn(move || {
+                for j in 0..5 {
+                    let profile = format!("concurrent-{}-{}", i, j);
+                    AppState::update(|state| state.oidc_tokens.insert(profile, token("token", None))).unwrap();
+                }
+            }))
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let state = AppState::load().unwrap();
+        let written = state.oidc_tokens.keys().filter(|profile| profile.starts_with("concurrent-")).count();
+        assert_eq!(written, 40);
+    }
+
+    #[test]
+    fn token_without_expiration_is_never_valid() {
+        let token = token("token", None);
+        assert!(!token.is_valid_for(Duration::zero()));
+        assert!(token.is_expired());
+    }
+
+    #[test]
+    fn is_valid_for_compares_the_remaining_validity() {
+        let token = token("token", Some(Utc::now() + Duration::minutes(10)));
+        assert!(token.is_valid_for(Duration::zero()));
+        assert!(token.is_valid_for(Duration::minutes(9)));
+        assert!(!token.is_valid_for(Duration::minutes(11)));
+        assert!(!token.is_expired());
+    }
+
+    #[test]
+    fn token_within_the_grace_period_is_expired() {
+        assert!(token("token", Some(Utc::now() + Duration::seconds(5))).is_expired());
+        assert!(token("token", Some(Utc::now() - Duration::seconds(5))).is_expired());
+        assert!(!token("token", Some(Utc::now() + Duration::seconds(30))).is_expired());
+    }
 }
@@ -0,0 +1,615 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Mutex;
+use anyhow::{anyhow, bail, Context};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use log::{info, debug, warn};
+use serde::{Deserialize, Serialize};
+use ssh_key::rand_core::{OsRng, RngCore};
+
+use crate::config::Config;
+use crate::files;
+use crate::state::{cache_path, AppState, ServiceToken, ServiceTokenCache, TokenStore};
+
+/// Passphrase of the encrypted token storage, prompted for when not set
+const PASSPHRASE_ENV: &str = "CSCS_KEY_PASSPHRASE";
+const ENCRYPTED_FILE: &str = "tokens.enc";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+// Tokens of the memory storage, gone when the process exits
+static MEMORY: Mutex<StoredTokens> = Mutex::new(StoredTokens::new());
+// Key of the encrypted storage, derived at most once per process
+static UNLOCKED: Mutex<Option<UnlockedKey>> = Mutex::new(None);
+
+/// Backend keeping the OIDC tokens of each profile, selected by `Config::token_storage`
+pub enum TokenStorage {
+    /// token.json in the cache directory, readable only by the owner
+    File,
+    /// User keyring of the Linux kernel, cleared when the last session of the user ends
+    #[cfg(target_os = "linux")]
+    Keyring,
+    /// tokens.enc in the cache directory, encrypted with a key from a passphrase or the ssh-agent
+    Encrypted(KeySource),
+    /// Memory of this process only, useful for long running commands like `agent` and `daemon`
+    Memory,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KeySource {
+    /// Argon2id hash of a passphrase
+    Passphrase,
+    /// Hash of a signature made by an Ed25519 or RSA key in the ssh-agent
+    SshAgent,
+}
+
+/// Tokens held by the encrypted and the memory storage
+#[derive(Serialize, Deserialize, Default)]
+struct StoredTokens {
+    /// Interactive tokens by profile name
+    #[serde(default)]
+    profiles: BTreeMap<String, TokenStore>,
+    /// Service-account tokens by API key fingerprint, never mixed with the interactive ones
+    #[serde(default)]
+    service_tokens: BTreeMap<String, ServiceToken>,
+}
+
+// Layout of tokens.enc, the plaintext is the JSON of `StoredTokens`
+#[derive(Serialize, Deserialize)]
+struct EncryptedTokens {
+    key_source: String,
+    /// SHA256 fingerprint of the ssh-agent key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    agent_key: Option<String>,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Clone)]
+struct UnlockedKey {
+    source: KeySource,
+    salt: Vec<u8>,
+    agent_key: Option<String>,
+    key: [u8; 32],
+}
+
+impl TokenStorage {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        match config.token_storage.as_str() {
+            "file" => Ok(Self::File),
+            #[cfg(target_os = "linux")]
+            "keyring" => Ok(Self::Keyring),
+            #[cfg(not(target_os = "linux"))]
+            "keyring" => bail!("The keyring token storage is only available on Linux"),
+            "encrypted" => Ok(Self::Encrypted(KeySource::parse(&config.token_encryption)?)),
+            "memory" => Ok(Self::Memory),
+            other => bail!("Unknown token storage '{}'. Use file, keyring, encrypted or memory.", other),
+        }
+    }
+
+    pub fn load(&self, profile: &str) -> anyhow::Result<Option<TokenStore>> {
+        // The memory storage only lives as long as this process, moving the token there would log the user out
+        if !matches!(self, Self::File | Self::Memory) {
+            self.migrate_from_file(profile)?;
+        }
+        self.load_stored(profile)
+    }
+
+    fn load_stored(&self, profile: &str) -> anyhow::Result<Option<TokenStore>> {
+        match self {
+            Self::File => Ok(AppState::load()?.oidc_tokens.remove(profile)),
+            #[cfg(target_os = "linux")]
+            Self::Keyring => keyring::load(&keyring::profile_key(profile)),
+            Self::Encrypted(_) => Ok(load_encrypted()?.profiles.remove(profile)),
+            Self::Memory => Ok(MEMORY.lock().unwrap().profiles.get(profile).cloned()),
+        }
+    }
+
+    pub fn save(&self, profile: &str, token: TokenStore) -> anyhow::Result<()> {
+        match self {
+            Self::File => {
+                AppState::update(|state| state.oidc_tokens.insert(profile.to_string(), token))?;
+                Ok(())
+            }
+            #[cfg(target_os = "linux")]
+            Self::Keyring => keyring::save(&keyring::profile_key(profile), &token),
+            Self::Encrypted(source) => {
+                update_encrypted(*source, |tokens| tokens.profiles.insert(profile.to_string(), token))?;
+                Ok(())
+            }
+            Self::Memory => {
+                MEMORY.lock().unwrap().profiles.insert(profile.to_string(), token);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn remove(&self, profile: &str) -> anyhow::Result<()> {
+        match self {
+            Self::File => {
+                AppState::update(|state| state.oidc_tokens.remove(profile))?;
+                Ok(())
+            }
+            #[cfg(target_os = "linux")]
+            Self::Keyring => keyring::remove(&keyring::profile_key(profile)),
+            Self::Encrypted(source) => {
+                update_encrypted(*source, |tokens| tokens.profiles.remove(profile))?;
+                Ok(())
+            }
+            Self::Memory => {
+                MEMORY.lock().unwrap().profiles.remove(profile);
+                Ok(())
+            }
+        }
+    }
+
+    /// Service-account token of the API key with `fingerprint`. A plaintext copy left by the file
+    /// storage is dropped rather than moved, the API key cheaply issues a new token.
+    pub fn load_service(&self, fingerprint: &str) -> anyhow::Result<Option<ServiceToken>> {
+        if !matches!(self, Self::File | Self::Memory) && ServiceTokenCache::load()?.tokens.contains_key(fingerprint) {
+            info!("Removing the service-account token from the plaintext token file");
+            Self::File.remove_service(fingerprint)?;
+        }
+        match self {
+            Self::File => Ok(ServiceTokenCache::load()?.tokens.remove(fingerprint)),
+            #[cfg(target_os = "linux")]
+            Self::Keyring => keyring::load(&keyring::service_key(fingerprint)),
+            Self::Encrypted(_) => Ok(load_encrypted()?.service_tokens.remove(fingerprint)),
+            Self::Memory => Ok(MEMORY.lock().unwrap().service_tokens.get(fingerprint).cloned()),
+        }
+    }
+
+    pub fn save_service(&self, fingerprint: &str, token: ServiceToken) -> anyhow::Result<()> {
+        match self {
+            Self::File => {
+                ServiceTokenCache::update(|cache| {
+                    cache.tokens.retain(|_, cached| !cached.token.is_expired());
+                    cache.tokens.insert(fingerprint.to_string(), token);
+                })?;
+                Ok(())
+            }
+            #[cfg(target_os = "linux")]
+            Self::Keyring => keyring::save(&keyring::service_key(fingerprint), &token),
+            Self::Encrypted(source) => {
+                update_encrypted(*source, |tokens| {
+                    tokens.service_tokens.retain(|_, cached| !cached.token.is_expired());
+                    tokens.service_tokens.insert(fingerprint.to_string(), token);
+                })
+            }
+            Self::Memory => {
+                MEMORY.lock().unwrap().service_tokens.insert(fingerprint.to_string(), token);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn remove_service(&self, fingerprint: &str) -> anyhow::Result<()> {
+        match self {
+            Self::File => {
+                ServiceTokenCache::update(|cache| cache.tokens.remove(fingerprint))?;
+                Ok(())
+            }
+            #[cfg(target_os = "linux")]
+            Self::Keyring => keyring::remove(&keyring::service_key(fingerprint)),
+            Self::Encrypted(source) => {
+                update_encrypted(*source, |tokens| tokens.service_tokens.remove(fingerprint))?;
+                Ok(())
+            }
+            Self::Memory => {
+                MEMORY.lock().unwrap().service_tokens.remove(fingerprint);
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove the token of `profile` from every backend, so that no copy saved under an earlier
+    /// configuration survives a logout. Only failures of the configured backend are errors.
+    pub fn remove_everywhere(&self, profile: &str) -> anyhow::Result<()> {
+        self.remove(profile)?;
+        for other in self.others()? {
+            if let Err(e) = other.remove(profile) {
+                warn!("Failed to remove the token of profile '{}' from the {} storage: {:#}", profile, other.name(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the service-account token of the API key with `fingerprint` from every backend
+    pub fn remove_service_everywhere(&self, fingerprint: &str) -> anyhow::Result<()> {
+        self.remove_service(fingerprint)?;
+        for other in self.others()? {
+            if let Err(e) = other.remove_service(fingerprint) {
+                warn!("Failed to remove the service-account token from the {} storage: {:#}", other.name(), e);
+            }
+        }
+        Ok(())
+    }
+
+    // Persistent backends other than this one that may hold tokens
+    fn others(&self) -> anyhow::Result<Vec<Self>> {
+        let mut others = vec![Self::File];
+        #[cfg(target_os = "linux")]
+        others.push(Self::Keyring);
+        if cache_path(ENCRYPTED_FILE)?.exists() {
+            others.push(Self::Encrypted(stored_key_source()?));
+        }
+        others.retain(|other| other.name() != self.name());
+        Ok(others)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::File => "file",
+            #[cfg(target_os = "linux")]
+            Self::Keyring => "keyring",
+            Self::Encrypted(_) => "encrypted",
+            Self::Memory => "memory",
+        }
+    }
+
+    /// Keep other processes from renewing or replacing the token of `profile` until the lock is dropped
+    pub fn lock(&self, profile: &str) -> anyhow::Result<files::FileLock> {
+        files::lock(&cache_path(&format!("token-{}", profile))?)
+    }
+
+    /// Move a token left in token.json by the file storage into this backend, so it does not stay on disk
+    /// in plaintext. A token already in this backend is kept, the one from the file is dropped.
+    fn migrate_from_file(&self, profile: &str) -> anyhow::Result<()> {
+        if !AppState::load()?.oidc_tokens.contains_key(profile) {
+            return Ok(());
+        }
+        AppState::update(|state| {
+            let Some(token) = state.oidc_tokens.get(profile) else {
+                return Ok(());
+            };
+            if self.load_stored(profile)?.is_none() {
+                info!("Moving the token of profile '{}' out of the plaintext token file", profile);
+                self.save(profile, token.clone())?;
+            }
+            state.oidc_tokens.remove(profile);
+            Ok(())
+        })?
+    }
+
+    /// Fail if a process without a terminal, started by this one, could not reach the tokens
+    pub fn check_background(&self) -> anyhow::Result<()> {
+        match self {
+            Self::Memory => bail!("Tokens in memory storage are not passed to a background process, use --foreground"),
+            Self::Encrypted(KeySource::Passphrase) if std::env::var_os(PASSPHRASE_ENV).is_none() => {
+                bail!("A background process cannot prompt for the token passphrase, set {} or use --foreground", PASSPHRASE_ENV)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl KeySource {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "passphrase" => Ok(Self::Passphrase),
+            "ssh-agent" => Ok(Self::SshAgent),
+            other => bail!("Unknown token encryption '{}'. Use passphrase or ssh-agent.", other),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Passphrase => "passphrase",
+            Self::SshAgent => "ssh-agent",
+        }
+    }
+}
+
+// Key source tokens.enc was written with, to rewrite it without asking for a new passphrase
+fn stored_key_source() -> anyhow::Result<KeySource> {
+    let path = cache_path(ENCRYPTED_FILE)?;
+    let file: EncryptedTokens = serde_json::from_str(&fs::read_to_string(&path)?)
+        .with_context(|| format!("Invalid encrypted token file {}", path.display()))?;
+    KeySource::parse(&file.key_source)
+}
+
+impl StoredTokens {
+    const fn new() -> Self {
+        Self { profiles: BTreeMap::new(), service_tokens: BTreeMap::new() }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.profiles.is_empty() && self.service_tokens.is_empty()
+    }
+}
+
+// Load, modify and save tokens.enc while holding its lock, removing the file once it holds no tokens
+fn update_encrypted<R>(source: KeySource, f: impl FnOnce(&mut StoredTokens) -> R) -> anyhow::Result<R> {
+    let path = cache_path(ENCRYPTED_FILE)?;
+    let _lock = files::lock(&path)?;
+    let mut tokens = load_encrypted()?;
+    let result = f(&mut tokens);
+    if !tokens.is_empty() {
+        save_encrypted(source, &tokens)?;
+    } else if path.exists() {
+        info!("Removing {}", path.display());
+        fs::remove_file(&path)?;
+    }
+    Ok(result)
+}
+
+fn load_encrypted() -> anyhow::Result<StoredTokens> {
+    let path = cache_path(ENCRYPTED_FILE)?;
+    info!("Trying to load tokens from {}", path.display());
+    if !path.exists() {
+        return Ok(StoredTokens::default());
+    }
+    let file: EncryptedTokens = serde_json::from_str(&fs::read_to_string(&path)?)
+        .with_context(|| format!("Invalid encrypted token file {}", path.display()))?;
+    let salt = BASE64.decode(&file.salt).context("Invalid salt in the encrypted token file")?;
+    let nonce = BASE64.decode(&file.nonce).context("Invalid nonce in the encrypted token file")?;
+    let ciphertext = BASE64.decode(&file.ciphertext).context("Invalid ciphertext in the encrypted token file")?;
+    let nonce: [u8; NONCE_LEN] = nonce.try_into()
+        .map_err(|_| anyhow!("Invalid nonce in the encrypted token file"))?;
+
+    // Decrypt with the key the file was written with, even if the configuration changed since
+    let key = unlock(KeySource::parse(&file.key_source)?, salt, file.agent_key, false)?;
+    let plaintext = ChaCha20Poly1305::new(&Key::from(key.key))
+        .decrypt(&Nonce::from(nonce), ciphertext.as_slice())
+        .map_err(|_| {
+            *UNLOCKED.lock().unwrap() = None;
+            anyhow!("Failed to decrypt {}, wrong {}?", path.display(), match key.source {
+                KeySource::Passphrase => "passphrase",
+                KeySource::SshAgent => "ssh-agent key",
+            })
+        })?;
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+fn save_encrypted(source: KeySource, tokens: &StoredTokens) -> anyhow::Result<()> {
+    let cached = UNLOCKED.lock().unwrap().clone().filter(|key| key.source == source);
+    let key = match cached {
+        Some(key) => key,
+        None => {
+            let mut salt = vec![0; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            unlock(source, salt, None, true)?
+        }
+    };
+
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let plaintext = serde_json::to_vec(tokens)?;
+    let ciphertext = ChaCha20Poly1305::new(&Key::from(key.key))
+        .encrypt(&Nonce::from(nonce), plaintext.as_slice())
+        .map_err(|_| anyhow!("Failed to encrypt the tokens"))?;
+
+    let file = EncryptedTokens {
+        key_source: source.name().to_string(),
+        agent_key: key.agent_key,
+        salt: BASE64.encode(&key.salt),
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    };
+    let path = cache_path(ENCRYPTED_FILE)?;
+    info!("Saving tokens to {}", path.display());
+    files::write_atomic(&path, serde_json::to_string_pretty(&file)?.as_bytes(), files::PRIVATE)
+}
+
+// Derive the encryption key, or reuse the one derived earlier by this process
+fn unlock(source: KeySource, salt: Vec<u8>, agent_key: Option<String>, new: bool) -> anyhow::Result<UnlockedKey> {
+    let mut unlocked = UNLOCKED.lock().unwrap();
+    if let Some(key) = unlocked.as_ref()
+        && key.source == source
+        && key.salt == salt
+    {
+        return Ok(key.clone());
+    }
+
+    let mut key = [0; 32];
+    let agent_key = match source {
+        KeySource::Passphrase => {
+            let passphrase = read_passphrase(new)?;
+            argon2::Argon2::default()
+                .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+                .map_err(|e| anyhow!("Failed to derive the token encryption key: {}", e))?;
+            None
+        }
+        KeySource::SshAgent => {
+            let (fingerprint, signature) = agent_signature(&salt, agent_key.as_deref())?;
+            key.copy_from_slice(&ssh_key::HashAlg::Sha256.digest(&signature));
+            Some(fingerprint)
+        }
+    };
+
+    let key = UnlockedKey { source, salt, agent_key, key };
+    *unlocked = Some(key.clone());
+    Ok(key)
+}
+
+fn read_passphrase(confirm: bool) -> anyhow::Result<String> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        debug!("Using the token passphrase from {}", PASSPHRASE_ENV);
+        return Ok(passphrase);
+    }
+    let passphrase = rpassword::prompt_password("Token storage passphrase: ")
+        .with_context(|| format!("Failed to read the token passphrase, set {} when no terminal is available", PASSPHRASE_ENV))?;
+    if passphrase.is_empty() {
+        bail!("The token passphrase must not be empty");
+    }
+    if confirm && rpassword::prompt_password("Repeat the passphrase: ")? != passphrase {
+        bail!("The passphrases do not match");
+    }
+    Ok(passphrase)
+}
+
+// Sign the salt with a key of the ssh-agent, returning the key fingerprint and the signature.
+// Only Ed25519 and RSA (PKCS#1 v1.5) signatures are deterministic, and certificates change on renewal.
+#[cfg(unix)]
+fn agent_signature(salt: &[u8], fingerprint: Option<&str>) -> anyhow::Result<(String, Vec<u8>)> {
+    use ssh_key::{Algorithm, HashAlg, PublicKey};
+    use crate::agent::{AgentClient, SSH_AGENT_RSA_SHA2_256};
+
+    let mut agent = AgentClient::from_env()?;
+    let identities = agent.identities()?;
+    let mut candidates = identities.iter().filter_map(|identity| {
+        let key = PublicKey::from_bytes(&identity.key_blob).ok()?;
+        let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+        matches!(key.algorithm(), Algorithm::Ed25519 | Algorithm::Rsa { .. })
+            .then_some((identity, key.algorithm(), fingerprint))
+    });
+    let (identity, algorithm, fingerprint) = match fingerprint {
+        Some(fingerprint) => candidates.find(|candidate| candidate.2 == fingerprint)
+            .with_context(|| format!("The tokens are encrypted with the ssh-agent key {}, which is not loaded", fingerprint))?,
+        None => candidates.min_by_key(|candidate| candidate.1 != Algorithm::Ed25519)
+            .context("The ssh-agent holds no Ed25519 or RSA key to encrypt the tokens with")?,
+    };
+    info!("Deriving the token encryption key from ssh-agent key {}", fingerprint);
+
+    let flags = if algorithm == Algorithm::Ed25519 { 0 } else { SSH_AGENT_RSA_SHA2_256 };
+    let mut challenge = b"cscs-key token encryption:".to_vec();
+    challenge.extend_from_slice(salt);
+    let signature = agent.sign(&identity.key_blob, &challenge, flags)?;
+    Ok((fingerprint, signature))
+}
+
+#[cfg(not(unix))]
+fn agent_signature(_salt: &[u8], _fingerprint: Option<&str>) -> anyhow::Result<(String, Vec<u8>)> {
+    bail!("Encrypting the tokens with an ssh-agent key is only supported on Unix")
+}
+
+#[cfg(target_os = "linux")]
+mod keyring {
+    use anyhow::Context;
+    use linux_keyutils::{KeyError, KeyPermissionsBuilder, KeyRing, KeyRingIdentifier, Permission};
+    use log::info;
+    use serde::Serialize;
+    use serde::de::DeserializeOwned;
+
+    /// Description of the kernel key holding the tokens of `profile`
+    pub fn profile_key(profile: &str) -> String {
+        format!("cscs-key:{}", profile)
+    }
+
+    /// Description of the kernel key holding a service-account token, distinct from every profile key
+    pub fn service_key(fingerprint: &str) -> String {
+        format!("cscs-key-service:{}", fingerprint)
+    }
+
+    fn user_keyring() -> anyhow::Result<KeyRing> {
+        KeyRing::from_special_id(KeyRingIdentifier::User, true)
+            .context("Failed to open the user keyring of the kernel")
+    }
+
+    pub fn load<T: DeserializeOwned>(description: &str) -> anyhow::Result<Option<T>> {
+        let key = match user_keyring()?.search(description) {
+            Ok(key) => key,
+            Err(KeyError::KeyDoesNotExist) => return Ok(None),
+            Err(e) => return Err(e).context("Failed to search the kernel keyring"),
+        };
+        info!("Loading token from kernel key {}", description);
+        let payload = key.read_to_vec().context("Failed to read the token from the kernel keyring")?;
+        Ok(Some(serde_json::from_slice(&payload)?))
+    }
+
+    pub fn save<T: Serialize>(description: &str, token: &T) -> anyhow::Result<()> {
+        info!("Saving token to kernel key {}", description);
+        let payload = serde_json::to_vec(token)?;
+        let key = user_keyring()?.add_key(description, &payload)
+            .context("Failed to store the token in the kernel keyring")?;
+        // Readable by processes of the user that do not possess the keyring, e.g. systemd services
+        let permissions = KeyPermissionsBuilder::builder()
+            .posessor(Permission::ALL)
+            .user(Permission::VIEW | Permission::READ | Permission::WRITE | Permission::SEARCH | Permission::SETATTR)
+            .build();
+        key.set_perms(permissions).context("Failed to set the permissions of the kernel key")?;
+        Ok(())
+    }
+
+    pub fn remove(description: &str) -> anyhow::Result<()> {
+        match user_keyring()?.search(description) {
+            Ok(key) => {
+                info!("Removing kernel key {}", description);
+                key.invalidate().context("Failed to remove the token from the kernel keyring")
+            }
+            Err(KeyError::KeyDoesNotExist) => Ok(()),
+            Err(e) => Err(e).context("Failed to search the kernel keyring"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::TestCacheDir;
+
+    #[test]
+    fn plaintext_token_moves_out_of_the_file() {
+        let _cache = TestCacheDir::new();
+        let profile = "migration-test";
+        let token = TokenStore {
+            access_token: "plaintext-access-token".to_string(),
+            refresh_token: Some("plaintext-refresh-token".to_string()),
+            id_token: None,
+            expiration: None,
+        };
+        TokenStorage::File.save(profile, token).unwrap();
+
+        // Stands in for the key derived from the passphrase, which would be prompted for
+        *UNLOCKED.lock().unwrap() = Some(UnlockedKey {
+            source: KeySource::Passphrase,
+            salt: vec![0; SALT_LEN],
+            agent_key: None,
+            key: [7; 32],
+        });
+        let encrypted = TokenStorage::Encrypted(KeySource::Passphrase);
+        let migrated = encrypted.load(profile).unwrap().unwrap();
+        assert_eq!(migrated.access_token, "plaintext-access-token");
+        assert!(encrypted.load(profile).unwrap().is_some());
+
+        assert!(!AppState::load().unwrap().oidc_tokens.contains_key(profile));
+        let file = fs::read_to_string(cache_path("token.json").unwrap()).unwrap();
+        assert!(!file.contains("plaintext-refresh-token"));
+        assert!(TokenStorage::File.load(profile).unwrap().is_none());
+        encrypted.remove(profile).unwrap();
+    }
+
+    #[test]
+    fn memory_storage_leaves_the_file_alone() {
+        let _cache = TestCacheDir::new();
+        let profile = "memory-test";
+        let token = TokenStore {
+            access_token: "file-access-token".to_string(),
+            refresh_token: Some("file-refresh-token".to_string()),
+            id_token: None,
+            expiration: None,
+        };
+        TokenStorage::File.save(profile, token).unwrap();
+
+        assert!(TokenStorage::Memory.load(profile).unwrap().is_none());
+        let kept = TokenStorage::File.load(profile).unwrap().unwrap();
+        assert_eq!(kept.refresh_token.as_deref(), Some("file-refresh-token"));
+        TokenStorage::File.remove(profile).unwrap();
+    }
+
+    #[test]
+    fn service_tokens_are_kept_apart_from_profile_tokens() {
+        let fingerprint = "service-test";
+        let token = ServiceToken {
+            token_url: "https://example.org/token".to_string(),
+            token: TokenStore {
+                access_token: "service-access-token".to_string(),
+                refresh_token: None,
+                id_token: None,
+                expiration: None,
+            },
+        };
+        TokenStorage::Memory.save_service(fingerprint, token).unwrap();
+
+        assert!(TokenStorage::Memory.load(fingerprint).unwrap().is_none());
+        let stored = TokenStorage::Memory.load_service(fingerprint).unwrap().unwrap();
+        assert_eq!(stored.token.access_token, "service-access-token");
+
+        TokenStorage::Memory.remove_service(fingerprint).unwrap();
+        assert!(TokenStorage::Memory.load_service(fingerprint).unwrap().is_none());
+    }
+}